@@ -0,0 +1,63 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+
+#[cfg(test)]
+extern crate alloc;
+
+#[cfg(not(test))]
+use ckb_std::default_alloc;
+#[cfg(not(test))]
+ckb_std::entry!(program_entry);
+#[cfg(not(test))]
+default_alloc!();
+
+use ckb_hash::blake2b_256;
+use ckb_std::{debug, env::argv, high_level::decode_hex};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+
+/// Error codes of ckb-auth
+#[repr(i8)]
+enum AuthError {
+    NotImplemented = 100,
+    Mismatched = 101,
+    InvalidArg = 102,
+}
+
+/// Algorithm id of secp256k1 with blake160 pubkey hashes, as the default CKB lock
+const ALGORITHM_CKB: u8 = 0;
+
+pub fn program_entry() -> i8 {
+    match verify() {
+        Ok(_) => 0,
+        Err(err) => err as i8,
+    }
+}
+
+/// Exec entry of ckb-auth, argv is <algorithm id><signature><message><pubkey hash>,
+/// each one hex encoded. Only the CKB algorithm is implemented.
+fn verify() -> Result<(), AuthError> {
+    let argv = argv();
+    if argv.len() != 4 {
+        return Err(AuthError::InvalidArg);
+    }
+    let decode = |index: usize| decode_hex(&argv[index]).map_err(|_| AuthError::InvalidArg);
+    let (algorithm_id, signature, message, pubkey_hash) =
+        (decode(0)?, decode(1)?, decode(2)?, decode(3)?);
+    if algorithm_id != [ALGORITHM_CKB] {
+        return Err(AuthError::NotImplemented);
+    }
+    if signature.len() != 65 || message.len() != 32 || pubkey_hash.len() != 20 {
+        return Err(AuthError::InvalidArg);
+    }
+
+    let recovery_id = RecoveryId::from_byte(signature[64]).ok_or(AuthError::InvalidArg)?;
+    let signature = Signature::from_slice(&signature[..64]).map_err(|_| AuthError::InvalidArg)?;
+    let pubkey = VerifyingKey::recover_from_prehash(&message, &signature, recovery_id)
+        .map_err(|_| AuthError::Mismatched)?;
+    let recovered_hash = blake2b_256(pubkey.to_encoded_point(true).as_bytes());
+    debug!("recovered pubkey hash: {:?}", &recovered_hash[..20]);
+    if recovered_hash[..20] != pubkey_hash {
+        return Err(AuthError::Mismatched);
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use alloc::{vec, vec::Vec};
+use ckb_hash::new_blake2b;
+use ckb_std::{
+    ckb_constants::Source,
+    ckb_types::{bytes::Bytes, core::ScriptHashType, packed::WitnessArgs, prelude::*},
+    debug,
+    high_level::{
+        encode_hex, exec_cell, load_input_since, load_tx_hash, load_witness, load_witness_args,
+        QueryIter,
+    },
+};
+
+use crate::errors::ShadowLockError;
+
+/// Recoverable signature, <r, 32 bytes><s, 32 bytes><recovery id, 1 byte>
+const SIGNATURE_SIZE: usize = 65;
+
+/// ckb-auth algorithm id of secp256k1 with blake160 pubkey hashes, as the default CKB lock
+const ALGORITHM_CKB: u8 = 0;
+
+/// Key verified by executing a ckb-auth deployment from cell deps
+#[derive(Debug)]
+pub struct Auth {
+    pub code_hash: [u8; 32],
+    pub hash_type: ScriptHashType,
+    pub pubkey_hash: [u8; 20],
+}
+
+impl Auth {
+    /// <ckb-auth code hash, 32 bytes><hash type, 1 byte><pubkey hash, 20 bytes>
+    pub fn unpack(value: &[u8]) -> Result<Auth, ShadowLockError> {
+        if value.len() != 53 {
+            return Err(ShadowLockError::Encoding);
+        }
+        let hash_type = match value[32] {
+            0 => ScriptHashType::Data,
+            1 => ScriptHashType::Type,
+            2 => ScriptHashType::Data1,
+            4 => ScriptHashType::Data2,
+            _ => return Err(ShadowLockError::Encoding),
+        };
+        Ok(Auth {
+            code_hash: value[0..32].try_into().unwrap(),
+            hash_type,
+            pubkey_hash: value[33..53].try_into().unwrap(),
+        })
+    }
+
+    /// Exec ckb-auth to verify the signature of the shadow cell group by the key, see
+    /// `load_signature`. Returns `missing` if the group has no signature, the ckb-auth
+    /// result is final otherwise.
+    pub fn verify(
+        &self,
+        missing: ShadowLockError,
+    ) -> Result<core::convert::Infallible, ShadowLockError> {
+        let signed = load_signature()?.ok_or(missing)?;
+        // <algorithm id><signature><message><pubkey hash>, hex encoded as ckb-auth takes them
+        let argv = [
+            encode_hex(&[ALGORITHM_CKB]),
+            encode_hex(&signed.signature),
+            encode_hex(&signed.message),
+            encode_hex(&self.pubkey_hash),
+        ];
+        let argv = argv.iter().map(|arg| arg.as_c_str()).collect::<Vec<_>>();
+        Ok(exec_cell(&self.code_hash, self.hash_type, &argv)?)
+    }
+}
+
+struct Signed {
+    signature: Vec<u8>,
+    message: [u8; 32],
+}
+
+/// Signature in the lock of the first witness of the shadow cell group, and the message it
+/// signs as secp256k1-blake160-sighash-all: the tx hash followed by that witness with its lock
+/// zeroed, then the other witnesses of the group and the witnesses without inputs, each one
+/// prefixed by its length as u64 little endian. None if the lock is not a signature.
+fn load_signature() -> Result<Option<Signed>, ShadowLockError> {
+    let witness = match load_witness_args(0, Source::GroupInput) {
+        Ok(witness) => witness,
+        Err(_) => return Ok(None),
+    };
+    let signature: Vec<u8> = match witness.lock().to_opt() {
+        Some(lock) if lock.len() == SIGNATURE_SIZE => lock.raw_data().to_vec(),
+        _ => return Ok(None),
+    };
+    let zeroed_witness: WitnessArgs = witness
+        .as_builder()
+        .lock(Some(Bytes::from(vec![0u8; SIGNATURE_SIZE])).pack())
+        .build();
+
+    let mut hasher = new_blake2b();
+    hasher.update(&load_tx_hash()?);
+    let mut update_witness = |witness: &[u8]| {
+        hasher.update(&(witness.len() as u64).to_le_bytes());
+        hasher.update(witness);
+    };
+    update_witness(zeroed_witness.as_slice());
+    QueryIter::new(load_witness, Source::GroupInput)
+        .skip(1)
+        .for_each(|witness| update_witness(&witness));
+    let mut index = QueryIter::new(load_input_since, Source::Input).count();
+    while let Ok(witness) = load_witness(index, Source::Input) {
+        update_witness(&witness);
+        index += 1;
+    }
+
+    let mut message = [0u8; 32];
+    hasher.finalize(&mut message);
+    debug!("sighash all message: {:?}", message);
+    Ok(Some(Signed { signature, message }))
+}
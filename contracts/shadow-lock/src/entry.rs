@@ -1,21 +1,39 @@
-use alloc::{borrow::ToOwned as _, vec::Vec};
+use alloc::{vec, vec::Vec};
 use ckb_std::{
     ckb_constants::Source,
-    ckb_types::prelude::Unpack,
-    debug,
-    high_level::{
-        load_cell_lock_hash, load_cell_type_hash, load_script, load_script_hash, QueryIter,
+    ckb_types::{
+        packed::Script,
+        prelude::{Builder, Entity, Pack, Unpack},
     },
+    debug,
+    high_level::{load_cell_lock_hash, load_script, load_script_hash, QueryIter},
 };
 
 use crate::{
     errors::ShadowLockError,
+    merkle::check_destination_allowlist,
+    time::{check_rate_limit, header_not_before, relative_since_reached, since_reached},
     utils::{
-        check_input_output_contain_same_cell, delegate_data_owner_check, unpack_script_args,
-        LoadHashTarget,
+        check_delegate_burned, check_delegate_preserved, check_expiry_kept, check_guardian,
+        check_input_output_contain_same_cell, check_lease_return, check_usage_limit,
+        count_present_delegates, find_present_delegate_cells, get_delegate_lock_hashes,
+        lease_return_args, lock_hash_in_inputs, unpack_script_args, Delegate, ExecDelegate,
+        RevocationRegistry, UnpackedShadowlockArgs,
     },
 };
 
+/// Who authorized the unlock, it decides where the shadow cells may go
+enum Unlocker<'a> {
+    /// the owner, or the fallback owner once the delegation expired
+    Owner([u8; 32]),
+    /// anyone settling the sale
+    Buyer,
+    /// enough delegates are presented
+    Delegate,
+    /// the signature of the exec delegate stands for the last missing delegate
+    ExecDelegate(&'a ExecDelegate),
+}
+
 pub fn main() -> Result<(), ShadowLockError> {
     let script_hash = load_script_hash()?;
 
@@ -31,21 +49,252 @@ pub fn main() -> Result<(), ShadowLockError> {
 
     debug!("unpacked args: {:?}", unpacked_args);
 
+    // the guardian can freeze every unlock, including the owner's
+    if let Some(guardian) = unpacked_args.extensions.guardian {
+        debug!("now do guardian check");
+        check_guardian(guardian)?;
+    }
+
+    // decide who unlocks first, the outputs allowed depend on it.
+    // revoked delegates do not count, owner unlocks do not need the registry
+    let mut revocation_registry = None;
+    let unlocker = match verify_owner(&unpacked_args, &shadow_in_input)? {
+        Some(unlocker) => unlocker,
+        None => {
+            if let Some(type_hash) = unpacked_args.extensions.revocation_registry {
+                revocation_registry = Some(RevocationRegistry::load(type_hash)?);
+            }
+            verify_delegates(
+                &unpacked_args,
+                &shadow_in_input,
+                revocation_registry.as_ref(),
+            )?
+        }
+    };
+
+    if matches!(unlocker, Unlocker::Delegate | Unlocker::ExecDelegate(_)) {
+        verify_delegate_restrictions(&script, &args, &unpacked_args, &shadow_in_input)?;
+    }
+
+    verify_outputs(
+        script_hash,
+        &unpacked_args,
+        &shadow_in_input,
+        &unlocker,
+        revocation_registry.as_ref(),
+    )?;
+
+    // exec never returns on success, the ckb-auth result is final,
+    // so the signatures must come after every other check
+    match unlocker {
+        Unlocker::ExecDelegate(exec_delegate) => {
+            debug!("now verify exec delegate signature");
+            exec_delegate
+                .auth
+                .verify(ShadowLockError::OwnershipVerificationFailure)?;
+        }
+        // possessing the delegate alone is not enough, the co-signer must sign the shadow cells
+        Unlocker::Delegate => {
+            if let Some(co_signer) = &unpacked_args.extensions.co_signer {
+                debug!("now do co-signature check");
+                co_signer.verify(ShadowLockError::CoSignatureVerificationFailure)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// The owner, the buyer or the fallback owner, if any of them unlocks
+fn verify_owner<'a>(
+    unpacked_args: &UnpackedShadowlockArgs,
+    shadow_in_input: &[usize],
+) -> Result<Option<Unlocker<'a>>, ShadowLockError> {
+    // the owner can always unlock, with or without the delegate
+    if let Some(owner) = unpacked_args
+        .extensions
+        .owner
+        .filter(|owner| lock_hash_in_inputs(*owner))
+    {
+        debug!("unlocked by owner");
+        return Ok(Some(Unlocker::Owner(owner)));
+    }
+
+    // anyone can settle the sale by paying the seller
+    if let Some(sale) = &unpacked_args.extensions.sale {
+        if sale.is_settled(shadow_in_input)? {
+            debug!("unlocked by sale");
+            return Ok(Some(Unlocker::Buyer));
+        }
+    }
+
+    // once expiry is proven, the delegate is rejected and only the fallback owner can unlock
+    if let Some(expiry) = &unpacked_args.extensions.expiry {
+        if since_reached(expiry.since)? {
+            debug!("delegation expired, now do fallback owner verification");
+            if !lock_hash_in_inputs(expiry.fallback_owner) {
+                return Err(ShadowLockError::FallbackOwnerVerificationFailure);
+            }
+            return Ok(Some(Unlocker::Owner(expiry.fallback_owner)));
+        }
+    }
+
+    Ok(None)
+}
+
+fn verify_delegates<'a>(
+    unpacked_args: &'a UnpackedShadowlockArgs,
+    shadow_in_input: &[usize],
+    revocation_registry: Option<&RevocationRegistry>,
+) -> Result<Unlocker<'a>, ShadowLockError> {
+    let delegate_cells = |delegate: &Delegate| {
+        find_present_delegate_cells(unpacked_args, delegate, revocation_registry)
+    };
+
+    // now let's do ownership verification, enough distinct delegates must be presented
+    let present_delegates = count_present_delegates(
+        &unpacked_args
+            .delegates
+            .iter()
+            .map(delegate_cells)
+            .collect::<Vec<_>>(),
+    );
+    debug!(
+        "present delegates: {}, threshold: {}",
+        present_delegates, unpacked_args.threshold
+    );
+    if present_delegates >= unpacked_args.threshold {
+        return Ok(Unlocker::Delegate);
+    }
+
+    // otherwise the heir can unlock, once the cell has been left untouched for the period
+    if let Some(heir) = &unpacked_args.extensions.heir {
+        if !delegate_cells(&heir.delegate).is_empty() {
+            debug!("now do heir period check");
+            if !relative_since_reached(shadow_in_input, heir.period)? {
+                return Err(ShadowLockError::HeirVerificationFailure);
+            }
+            return Ok(Unlocker::Delegate);
+        }
+    }
+
+    // the exec delegate counts for the last missing delegate, unless it is revoked
+    if let Some(exec_delegate) = &unpacked_args.extensions.exec_delegate {
+        let exec_lock_hash = exec_delegate.script_hash();
+        if present_delegates + 1 == unpacked_args.threshold
+            && !lock_hash_in_inputs(exec_lock_hash)
+            && revocation_registry.is_none_or(|registry| !registry.contains(&exec_lock_hash))
+        {
+            return Ok(Unlocker::ExecDelegate(exec_delegate));
+        }
+    }
+
+    if unpacked_args.extensions.owner.is_some() {
+        // neither the delegate nor the owner is presented
+        return Err(ShadowLockError::OwnerVerificationFailure);
+    }
+    Err(ShadowLockError::OwnershipVerificationFailure)
+}
+
+fn verify_delegate_restrictions(
+    script: &Script,
+    args: &[u8],
+    unpacked_args: &UnpackedShadowlockArgs,
+    shadow_in_input: &[usize],
+) -> Result<(), ShadowLockError> {
+    // the delegate may only unlock once the time window starts
+    let extensions = &unpacked_args.extensions;
+    if let Some(not_before) = extensions.not_before {
+        debug!("now do time window check");
+        header_not_before(not_before)?;
+    }
+
+    // the delegate can not move the cell out of reach of the fallback owner,
+    // the returned lease already stays under the expiring lock
+    if extensions.expiry.is_some() && extensions.lease.is_none() {
+        debug!("now do expiry check");
+        check_expiry_kept(shadow_in_input)?;
+    }
+
+    // the delegate may only act a limited number of times
+    if extensions.usage_limit {
+        debug!("now do usage limit check");
+        check_usage_limit(shadow_in_input)?;
+    }
+
+    // the renter must hand the cell back to the return lease lock
+    if extensions.lease.is_some() {
+        debug!("now do lease return check");
+        let return_lock = script
+            .clone()
+            .as_builder()
+            .args(lease_return_args(args, extensions).pack())
+            .build();
+        let return_lock_hash = return_lock
+            .calc_script_hash()
+            .as_slice()
+            .try_into()
+            .unwrap();
+        check_lease_return(shadow_in_input, return_lock_hash)?;
+    }
+
+    // the delegate may only unlock once per interval
+    if let Some(interval) = extensions.rate_limit {
+        debug!("now do rate limit check");
+        check_rate_limit(shadow_in_input, interval)?;
+    }
+
+    // the key cell may guard other shadow cells, so it must survive the unlock
+    if extensions.preserve_delegate {
+        debug!("now do delegate preservation check");
+        check_delegate_preserved(unpacked_args)?;
+    }
+
+    // one-shot key, it must be destroyed by the unlock
+    if extensions.burn_delegate {
+        debug!("now do delegate burn check");
+        check_delegate_burned(unpacked_args)?;
+    }
+
+    Ok(())
+}
+
+fn verify_outputs(
+    script_hash: [u8; 32],
+    unpacked_args: &UnpackedShadowlockArgs,
+    shadow_in_input: &[usize],
+    unlocker: &Unlocker,
+    revocation_registry: Option<&RevocationRegistry>,
+) -> Result<(), ShadowLockError> {
+    let owner = match unlocker {
+        Unlocker::Owner(owner) => Some(*owner),
+        _ => None,
+    };
+
     // if forbid trade, then this
     if unpacked_args.flags.forbid_trade {
         debug!("now do forbid trade verify");
-        for input_index in shadow_in_input.to_owned() {
+        // valid target lock hash is only: current lock hash, and delegate lock hash.
+        // for type or data delegates, the delegate lock hash is the lock of the input carrying it
+        // for owner unlock, the only other valid target is the owner lock hash
+        let mut allowed_lock_hashes = vec![script_hash];
+        if let Some(owner) = owner {
+            allowed_lock_hashes.push(owner);
+        } else {
+            allowed_lock_hashes.extend(get_delegate_lock_hashes(
+                unpacked_args,
+                revocation_registry,
+            )?);
+        }
+
+        for input_index in shadow_in_input.iter().copied() {
             let output_pos =
                 check_input_output_contain_same_cell(input_index, Source::GroupInput, true, false)?;
 
-            // valid target lock hash is only: current lock hash, and delegate lock hash
             for index in output_pos {
                 let output_lock_hash = load_cell_lock_hash(index, Source::Output)?;
-                let delegate_target = unpacked_args.flags.get_delegate_target();
-                if delegate_target == LoadHashTarget::Lock
-                    && (output_lock_hash != script_hash
-                        && output_lock_hash != unpacked_args.ref_hash)
-                {
+                if !allowed_lock_hashes.contains(&output_lock_hash) {
                     return Err(ShadowLockError::ForbidTradeVerificationFailure);
                 }
             }
@@ -55,7 +304,7 @@ pub fn main() -> Result<(), ShadowLockError> {
     // if self destruction is set, then this cell must be destroyed after unlock
     if unpacked_args.flags.self_destruction {
         debug!("now do self destruction check");
-        for input_index in shadow_in_input {
+        for input_index in shadow_in_input.iter().copied() {
             if !check_input_output_contain_same_cell(input_index, Source::GroupInput, true, false)?
                 .is_empty()
             {
@@ -64,34 +313,18 @@ pub fn main() -> Result<(), ShadowLockError> {
         }
     }
 
-    // now let's do ownership verification
-    let ownership_verification = match unpacked_args.flags.get_delegate_target() {
-        LoadHashTarget::Type => {
-            // type hash check
-            QueryIter::new(load_cell_type_hash, Source::Input)
-                .enumerate()
-                .any(|(index, type_hash)| {
-                    type_hash.unwrap_or_default() == unpacked_args.ref_hash
-		    // data hash check if needed
-                        && delegate_data_owner_check(unpacked_args.data_hash, index, Source::Input)
-                            .is_ok_and(|check_result| check_result)
-                })
-        }
-        LoadHashTarget::Lock => {
-            // lock hash check
-            QueryIter::new(load_cell_lock_hash, Source::Input)
-                .enumerate()
-                .any(|(index, lock_hash)| {
-                    lock_hash == unpacked_args.ref_hash &&
-		    // data hash check if needed
-			delegate_data_owner_check(unpacked_args.data_hash, index, Source::Input).map_or(false, |x| x )
-                })
-        }
-        _ => unreachable!(),
-    };
+    // outputs inheriting the shadow cell may only go to allowlisted locks
+    if let Some(root) = unpacked_args.extensions.destination_root {
+        debug!("now do destination allowlist check");
+        let mut exempt_lock_hashes = vec![script_hash];
+        exempt_lock_hashes.extend(owner);
+        check_destination_allowlist(shadow_in_input, root, &exempt_lock_hashes)?;
+    }
 
-    if !ownership_verification {
-        return Err(ShadowLockError::OwnershipVerificationFailure);
+    // every unlock pays the royalty, including the owner's
+    if let Some(royalty) = &unpacked_args.extensions.royalty {
+        debug!("now do royalty check");
+        royalty.check_royalty(shadow_in_input)?;
     }
 
     Ok(())
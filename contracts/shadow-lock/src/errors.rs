@@ -13,6 +13,22 @@ pub enum ShadowLockError {
 
     ForbidTradeVerificationFailure = -111,
     SelfDestructionVerificationFailure = -112,
+    FallbackOwnerVerificationFailure = -113,
+    ExpiryVerificationFailure = -114,
+    OwnerVerificationFailure = -115,
+    UnsupportedArgs = -116,
+    UsageLimitVerificationFailure = -117,
+    RateLimitVerificationFailure = -118,
+    DestinationVerificationFailure = -119,
+    RoyaltyVerificationFailure = -120,
+    DelegatePreservationFailure = -121,
+    DelegateBurnVerificationFailure = -122,
+    TimeWindowVerificationFailure = -123,
+    CoSignatureVerificationFailure = -124,
+    RevocationVerificationFailure = -125,
+    Frozen = -126,
+    LeaseVerificationFailure = -127,
+    HeirVerificationFailure = -128,
 }
 
 impl From<SysError> for ShadowLockError {
@@ -11,8 +11,11 @@ ckb_std::entry!(program_entry);
 #[cfg(not(test))]
 default_alloc!();
 
+mod auth;
 mod entry;
 mod errors;
+mod merkle;
+mod time;
 mod utils;
 
 pub fn program_entry() -> i8 {
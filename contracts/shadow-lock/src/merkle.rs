@@ -0,0 +1,92 @@
+use alloc::vec::Vec;
+use ckb_hash::new_blake2b;
+use ckb_std::{
+    ckb_constants::Source,
+    debug,
+    high_level::{load_cell_lock_hash, load_witness_args},
+};
+
+use crate::{errors::ShadowLockError, utils::check_input_output_contain_same_cell};
+
+/// Parent node of two merkle nodes, children are sorted so the proof needs no position bits
+fn merkle_parent(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    let mut hasher = new_blake2b();
+    hasher.update(left);
+    hasher.update(right);
+    let mut ret = [0u8; 32];
+    hasher.finalize(&mut ret);
+    ret
+}
+
+/// Fold a proof of <sibling count, 1 byte><sibling count * 32 bytes> from `leaf`,
+/// returns the computed root and the rest of proofs
+fn merkle_fold(leaf: [u8; 32], proofs: &[u8]) -> Result<([u8; 32], &[u8]), ShadowLockError> {
+    let Some((&count, proofs)) = proofs.split_first() else {
+        return Err(ShadowLockError::DestinationVerificationFailure);
+    };
+    let size = count as usize * 32;
+    if proofs.len() < size {
+        return Err(ShadowLockError::DestinationVerificationFailure);
+    }
+    let root = proofs[..size].chunks_exact(32).fold(leaf, |node, sibling| {
+        merkle_parent(&node, sibling.try_into().unwrap())
+    });
+    Ok((root, &proofs[size..]))
+}
+
+/// Every output inheriting type and data of a shadow cell must be locked by one of
+/// `exempt_lock_hashes`, or by a lock hash proven to be a leaf of the merkle `root`.
+/// Proofs are concatenated in `WitnessArgs.lock` of the first shadow cell witness,
+/// one per distinct non exempt lock hash, ordered by the first output locked by it.
+pub fn check_destination_allowlist(
+    shadow_in_input: &[usize],
+    root: [u8; 32],
+    exempt_lock_hashes: &[[u8; 32]],
+) -> Result<(), ShadowLockError> {
+    let mut destinations: Vec<usize> = Vec::new();
+    for input_index in shadow_in_input.iter().copied() {
+        destinations.extend(check_input_output_contain_same_cell(
+            input_index,
+            Source::GroupInput,
+            true,
+            false,
+        )?);
+    }
+    destinations.sort_unstable();
+    destinations.dedup();
+
+    let mut proven: Vec<[u8; 32]> = Vec::new();
+    for index in destinations {
+        let lock_hash = load_cell_lock_hash(index, Source::Output)?;
+        if !exempt_lock_hashes.contains(&lock_hash) && !proven.contains(&lock_hash) {
+            proven.push(lock_hash);
+        }
+    }
+    if proven.is_empty() {
+        return Ok(());
+    }
+
+    let proofs = load_witness_args(0, Source::GroupInput)
+        .map_err(|_| ShadowLockError::DestinationVerificationFailure)?
+        .lock()
+        .to_opt()
+        .ok_or(ShadowLockError::DestinationVerificationFailure)?
+        .raw_data();
+    let mut proofs = &proofs[..];
+    for lock_hash in proven {
+        let (computed_root, rest) = merkle_fold(lock_hash, proofs)?;
+        debug!(
+            "lock_hash: {:?}, computed_root: {:?}",
+            lock_hash, computed_root
+        );
+        if computed_root != root {
+            return Err(ShadowLockError::DestinationVerificationFailure);
+        }
+        proofs = rest;
+    }
+    if !proofs.is_empty() {
+        return Err(ShadowLockError::DestinationVerificationFailure);
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use ckb_std::{
+    ckb_constants::Source,
+    ckb_types::prelude::Unpack,
+    debug,
+    high_level::{load_cell_capacity, load_header, load_input_since, QueryIter},
+    since::{EpochNumberWithFraction, LockValue, Since},
+};
+
+use crate::{errors::ShadowLockError, utils::Successors};
+
+/// Absolute since value of the given header, measured in the same metric as `target`
+fn header_since(header: &ckb_std::ckb_types::packed::Header, target: Since) -> Option<Since> {
+    let raw = header.raw();
+    match target.extract_lock_value()? {
+        LockValue::BlockNumber(_) => Since::from_block_number(raw.number().unpack(), true),
+        LockValue::EpochNumberWithFraction(_) => {
+            let epoch: u64 = raw.epoch().unpack();
+            Some(Since::from_epoch(
+                EpochNumberWithFraction::from_full_value(epoch),
+                true,
+            ))
+        }
+        LockValue::Timestamp(_) => {
+            let timestamp: u64 = raw.timestamp().unpack();
+            // since timestamps are in seconds, header timestamps in milliseconds
+            Since::from_timestamp(timestamp / 1000, true)
+        }
+    }
+}
+
+/// Check if the transaction proves that chain time has reached the absolute since `target`.
+///
+/// CKB scripts can only prove a lower bound of time: either an input carries an absolute
+/// since no less than `target`, or a header dep is at or after `target`.
+pub fn since_reached(target: Since) -> Result<bool, ShadowLockError> {
+    let since_proved =
+        QueryIter::new(load_input_since, Source::Input).any(|since| Since::new(since) >= target);
+    if since_proved {
+        debug!("since reached by input since");
+        return Ok(true);
+    }
+
+    let header_proved = QueryIter::new(load_header, Source::HeaderDep).any(|header| {
+        header_since(&header, target).is_some_and(|header_since| header_since >= target)
+    });
+    if header_proved {
+        debug!("since reached by header deps");
+    }
+    Ok(header_proved)
+}
+
+/// Check if the header of an input, which must be in header deps, is at or after `not_before`.
+///
+/// The header proves that chain time has reached it, so `not_before` is a real lower bound.
+pub fn header_not_before(not_before: Since) -> Result<(), ShadowLockError> {
+    // inputs without their header in header deps are skipped
+    let input_count = QueryIter::new(load_input_since, Source::Input).count();
+    let reached = (0..input_count)
+        .filter_map(|index| load_header(index, Source::Input).ok())
+        .any(|header| header_since(&header, not_before).is_some_and(|since| since >= not_before));
+    if !reached {
+        return Err(ShadowLockError::TimeWindowVerificationFailure);
+    }
+    Ok(())
+}
+
+/// Check if every shadow cell is spent with a relative since no less than `period`,
+/// which proves none of them has been touched for the period.
+pub fn relative_since_reached(
+    shadow_in_input: &[usize],
+    period: Since,
+) -> Result<bool, ShadowLockError> {
+    for input_index in shadow_in_input.iter().copied() {
+        let since = Since::new(load_input_since(input_index, Source::GroupInput)?);
+        debug!("input_index: {input_index}, since: {:?}", since);
+        // a since with different metric or absolute since is not comparable
+        if !since.flags_is_valid() || since.partial_cmp(&period).is_none() || since < period {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Every shadow cell must be spent with a relative since no less than `interval`,
+/// and re-created with the same lock, type, capacity and data, which restarts the interval.
+pub fn check_rate_limit(shadow_in_input: &[usize], interval: Since) -> Result<(), ShadowLockError> {
+    if !relative_since_reached(shadow_in_input, interval)? {
+        return Err(ShadowLockError::RateLimitVerificationFailure);
+    }
+    let mut successors = Successors::default();
+    for input_index in shadow_in_input.iter().copied() {
+        let capacity = load_cell_capacity(input_index, Source::GroupInput)?;
+        let recreated =
+            successors.reserve(input_index, Source::GroupInput, true, true, |index| {
+                load_cell_capacity(index, Source::Output).is_ok_and(|output| output == capacity)
+            })?;
+        if !recreated {
+            return Err(ShadowLockError::RateLimitVerificationFailure);
+        }
+    }
+    Ok(())
+}
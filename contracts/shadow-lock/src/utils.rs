@@ -1,10 +1,22 @@
-use crate::errors::ShadowLockError;
+use crate::{auth::Auth, errors::ShadowLockError};
 use alloc::{vec, vec::Vec};
+use ckb_hash::blake2b_256;
 use ckb_std::{
     ckb_constants::Source,
+    ckb_types::{
+        core::ScriptHashType,
+        packed::{Byte, Script},
+        prelude::{Builder, Entity, Pack},
+    },
     debug,
-    high_level::{load_cell_data_hash, load_cell_lock_hash, load_cell_type_hash, QueryIter},
+    error::SysError,
+    high_level::{
+        load_cell_capacity, load_cell_data, load_cell_data_hash, load_cell_lock,
+        load_cell_lock_hash, load_cell_type, load_cell_type_hash, load_input_out_point, QueryIter,
+    },
+    since::{LockValue, Since},
 };
+use core::cmp::Ordering;
 
 #[derive(Debug)]
 pub struct FeatureFlags {
@@ -12,28 +24,50 @@ pub struct FeatureFlags {
     pub forbid_trade: bool,
     pub self_destruction: bool,
     pub restrict_delegate_data: bool,
+    pub delegate_cell_data: bool,
+    pub delegate_list: bool,
+    pub extensions: bool,
 }
 
-#[derive(Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum LoadHashTarget {
     Type,
     Lock,
-    #[allow(dead_code)]
-    Data, // Maybe?
+    Data,
 }
 
+/// If set in the first byte of args, it is a version header `0x80 | version`
+/// followed by the mode flags, otherwise the first byte is the mode flags of version 0
+const ARGS_VERSION_HEADER: u8 = 0b10000000;
+/// Latest args version, it adds the delegate cell data, delegate list and extensions flags
+const ARGS_VERSION: u8 = 1;
+/// Mode flag bits of version 0 args, the others are only valid behind the version header
+const V0_FLAG_BITS: u8 = 0b00001111;
+/// Mode flag bits not assigned to any feature yet
+const RESERVED_FLAG_BITS: u8 = 0b10000000;
+
 impl FeatureFlags {
-    pub fn unpack(flag_bits: u8) -> FeatureFlags {
-        FeatureFlags {
+    pub fn unpack(flag_bits: u8) -> Result<FeatureFlags, ShadowLockError> {
+        // a cell using a future feature must not unlock with old semantics
+        if flag_bits & RESERVED_FLAG_BITS != 0 {
+            return Err(ShadowLockError::UnsupportedArgs);
+        }
+        Ok(FeatureFlags {
             delegate_script_type: (flag_bits & 0b00000001) != 0,
             forbid_trade: (flag_bits & 0b00000010) != 0,
             self_destruction: (flag_bits & 0b00000100) != 0,
             restrict_delegate_data: (flag_bits & 0b00001000) != 0,
-        }
+            delegate_cell_data: (flag_bits & 0b00010000) != 0,
+            delegate_list: (flag_bits & 0b00100000) != 0,
+            extensions: (flag_bits & 0b01000000) != 0,
+        })
     }
 
     pub fn get_delegate_target(&self) -> LoadHashTarget {
-        if self.delegate_script_type {
+        // cell data delegation takes precedence over the script type bit
+        if self.delegate_cell_data {
+            LoadHashTarget::Data
+        } else if self.delegate_script_type {
             LoadHashTarget::Type
         } else {
             LoadHashTarget::Lock
@@ -41,32 +75,628 @@ impl FeatureFlags {
     }
 }
 
+/// Script matched by its code hash, hash type and args prefix instead of its full hash
+#[derive(Debug, Eq, PartialEq)]
+pub struct ScriptPattern {
+    pub code_hash: [u8; 32],
+    pub hash_type: u8,
+    pub args_prefix: Vec<u8>,
+}
+
+impl ScriptPattern {
+    pub fn matches(&self, script: &Script) -> bool {
+        script.code_hash().as_slice() == self.code_hash
+            && script.hash_type().as_slice()[0] == self.hash_type
+            && script.args().raw_data().starts_with(&self.args_prefix)
+    }
+}
+
+/// data, type, data1, data2
+fn valid_hash_type(hash_type: u8) -> bool {
+    matches!(hash_type, 0 | 1 | 2 | 4)
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Delegate {
+    /// full lock hash, type hash or data hash of the delegate cell
+    Hash(LoadHashTarget, [u8; 32]),
+    /// any cell whose type script matches the pattern, e.g. any NFT of a collection
+    TypePattern(ScriptPattern),
+    /// any cell whose lock script matches the pattern, e.g. any cell of a lock deployment
+    LockPattern(ScriptPattern),
+    /// cells of a UDT type hash holding at least the amount in total, e.g. staked governance tokens
+    TokenBalance([u8; 32], u128),
+}
+
+impl Delegate {
+    /// Type and data delegates are carried by a cell, lock delegates can hold any cell,
+    /// and a token balance can be spread over any number of cells
+    pub fn is_carried_by_cell(&self) -> bool {
+        !matches!(
+            self,
+            Delegate::Hash(LoadHashTarget::Lock, _)
+                | Delegate::LockPattern(_)
+                | Delegate::TokenBalance(..)
+        )
+    }
+
+    /// Unpack a delegate list entry, returns the delegate and the entry size
+    ///
+    /// kind 0, 1, 2: <kind, 1 byte><lock, type or data hash, 32 bytes>
+    /// kind 3: <kind, 1 byte><type code hash, 32 bytes><hash type, 1 byte><args prefix length, 1 byte><args prefix>
+    /// kind 4: <kind, 1 byte><lock code hash, 32 bytes><hash type, 1 byte>
+    /// kind 5: <kind, 1 byte><UDT type hash, 32 bytes><minimum amount, u128 little endian 16 bytes>
+    fn unpack(entry: &[u8]) -> Result<(Delegate, usize), ShadowLockError> {
+        if entry.is_empty() {
+            return Err(ShadowLockError::LengthNotEnough);
+        }
+        match entry[0] {
+            kind @ 0..=2 => {
+                if entry.len() < 33 {
+                    return Err(ShadowLockError::LengthNotEnough);
+                }
+                let target = match kind {
+                    0 => LoadHashTarget::Lock,
+                    1 => LoadHashTarget::Type,
+                    _ => LoadHashTarget::Data,
+                };
+                let ref_hash = entry[1..33].try_into().unwrap();
+                Ok((Delegate::Hash(target, ref_hash), 33))
+            }
+            3 => {
+                if entry.len() < 35 || entry.len() < 35 + entry[34] as usize {
+                    return Err(ShadowLockError::LengthNotEnough);
+                }
+                if !valid_hash_type(entry[33]) {
+                    return Err(ShadowLockError::Encoding);
+                }
+                let size = 35 + entry[34] as usize;
+                let pattern = ScriptPattern {
+                    code_hash: entry[1..33].try_into().unwrap(),
+                    hash_type: entry[33],
+                    args_prefix: entry[35..size].to_vec(),
+                };
+                Ok((Delegate::TypePattern(pattern), size))
+            }
+            4 => {
+                if entry.len() < 34 {
+                    return Err(ShadowLockError::LengthNotEnough);
+                }
+                if !valid_hash_type(entry[33]) {
+                    return Err(ShadowLockError::Encoding);
+                }
+                let pattern = ScriptPattern {
+                    code_hash: entry[1..33].try_into().unwrap(),
+                    hash_type: entry[33],
+                    args_prefix: Vec::new(),
+                };
+                Ok((Delegate::LockPattern(pattern), 34))
+            }
+            5 => {
+                if entry.len() < 49 {
+                    return Err(ShadowLockError::LengthNotEnough);
+                }
+                let type_hash = entry[1..33].try_into().unwrap();
+                let amount = u128::from_le_bytes(entry[33..49].try_into().unwrap());
+                Ok((Delegate::TokenBalance(type_hash, amount), 49))
+            }
+            _ => Err(ShadowLockError::UnsupportedArgs),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UnpackedShadowlockArgs {
     pub flags: FeatureFlags,
-    pub ref_hash: [u8; 32],
+    pub delegates: Vec<Delegate>,
+    /// how many distinct delegates must be present to unlock, each by a different cell
+    pub threshold: usize,
+    /// restricted delegate data, shared by every delegate of the list
     pub data_hash: Option<[u8; 32]>,
+    pub extensions: Extensions,
+}
+
+const EXTENSION_EXPIRY: u8 = 0x01;
+const EXTENSION_OWNER: u8 = 0x02;
+const EXTENSION_CELL_DEP_PROOF: u8 = 0x03;
+const EXTENSION_EXEC_DELEGATE: u8 = 0x04;
+const EXTENSION_USAGE_LIMIT: u8 = 0x05;
+const EXTENSION_RATE_LIMIT: u8 = 0x06;
+const EXTENSION_DESTINATION_ALLOWLIST: u8 = 0x07;
+const EXTENSION_ROYALTY: u8 = 0x08;
+const EXTENSION_PRESERVE_DELEGATE: u8 = 0x09;
+const EXTENSION_BURN_DELEGATE: u8 = 0x0a;
+const EXTENSION_DELEGATE_DATA_SLICE: u8 = 0x0b;
+const EXTENSION_NOT_BEFORE: u8 = 0x0c;
+const EXTENSION_NOT_AFTER: u8 = 0x0d;
+const EXTENSION_CO_SIGNER: u8 = 0x0e;
+const EXTENSION_REVOCATION_REGISTRY: u8 = 0x0f;
+const EXTENSION_GUARDIAN: u8 = 0x10;
+const EXTENSION_SALE: u8 = 0x11;
+const EXTENSION_LEASE: u8 = 0x12;
+const EXTENSION_HEIR: u8 = 0x13;
+
+/// After `since` is reached, the delegate is rejected and only `fallback_owner` can unlock
+#[derive(Debug, PartialEq, Eq)]
+pub struct Expiry {
+    pub since: Since,
+    pub fallback_owner: [u8; 32],
+}
+
+impl Expiry {
+    /// <absolute since, u64 little endian 8 bytes><fallback owner lock hash, 32 bytes>
+    fn unpack(value: &[u8]) -> Result<Expiry, ShadowLockError> {
+        if value.len() != 40 {
+            return Err(ShadowLockError::Encoding);
+        }
+        let since = unpack_since(&value[0..8], true)?;
+        Ok(Expiry {
+            since,
+            fallback_owner: value[8..40].try_into().unwrap(),
+        })
+    }
+}
+
+/// Unpack a since value from `value`, it must be valid with block number or epoch metric
+fn unpack_since(value: &[u8], absolute: bool) -> Result<Since, ShadowLockError> {
+    let since = unpack_any_since(value, absolute)?;
+    match since.extract_lock_value() {
+        Some(LockValue::BlockNumber(_)) | Some(LockValue::EpochNumberWithFraction(_)) => Ok(since),
+        _ => Err(ShadowLockError::Encoding),
+    }
+}
+
+/// Unpack a since value from `value`, it must be valid with any metric
+fn unpack_any_since(value: &[u8], absolute: bool) -> Result<Since, ShadowLockError> {
+    if value.len() != 8 {
+        return Err(ShadowLockError::Encoding);
+    }
+    let since = Since::new(u64::from_le_bytes(value.try_into().unwrap()));
+    if since.is_absolute() != absolute || !since.flags_is_valid() {
+        return Err(ShadowLockError::Encoding);
+    }
+    Ok(since)
+}
+
+/// Lock delegate proven by a signature of its key in the shadow cell witness, verified by
+/// ckb-auth, instead of requiring a cell locked by it
+#[derive(Debug)]
+pub struct ExecDelegate {
+    /// signature lock of the delegate, whose args are the pubkey hash
+    pub script: Script,
+    pub auth: Auth,
+}
+
+/// <code hash, 32 bytes><hash type, 1 byte><args>
+fn unpack_script(value: &[u8]) -> Result<Script, ShadowLockError> {
+    if value.len() < 33 {
+        return Err(ShadowLockError::LengthNotEnough);
+    }
+    if !valid_hash_type(value[32]) {
+        return Err(ShadowLockError::Encoding);
+    }
+    let code_hash: [u8; 32] = value[0..32].try_into().unwrap();
+    Ok(Script::new_builder()
+        .code_hash(code_hash.pack())
+        .hash_type(Byte::new(value[32]))
+        .args(value[33..].pack())
+        .build())
+}
+
+impl ExecDelegate {
+    /// <ckb-auth code hash, 32 bytes><hash type, 1 byte><pubkey hash, 20 bytes>
+    /// <delegate lock code hash, 32 bytes><hash type, 1 byte>
+    fn unpack(value: &[u8]) -> Result<ExecDelegate, ShadowLockError> {
+        if value.len() != 86 {
+            return Err(ShadowLockError::Encoding);
+        }
+        let auth = Auth::unpack(&value[0..53])?;
+        // the delegate lock args are the pubkey hash
+        let script = unpack_script(&[&value[53..86], &value[33..53]].concat())?;
+        Ok(ExecDelegate { script, auth })
+    }
+
+    pub fn script_hash(&self) -> [u8; 32] {
+        self.script
+            .calc_script_hash()
+            .as_slice()
+            .try_into()
+            .unwrap()
+    }
+}
+
+/// Region of the delegate cell data that the restricted data hash is checked against
+#[derive(Debug, Clone, Copy)]
+pub struct DataSlice {
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl DataSlice {
+    /// <offset, u32 little endian 4 bytes><length, u32 little endian 4 bytes>
+    fn unpack(value: &[u8]) -> Result<DataSlice, ShadowLockError> {
+        if value.len() != 8 {
+            return Err(ShadowLockError::Encoding);
+        }
+        let offset = u32::from_le_bytes(value[0..4].try_into().unwrap()) as usize;
+        let length = u32::from_le_bytes(value[4..8].try_into().unwrap()) as usize;
+        if length == 0 {
+            return Err(ShadowLockError::Encoding);
+        }
+        Ok(DataSlice { offset, length })
+    }
+
+    /// Hash of the slice of `data`, None if data is too short
+    fn hash(&self, data: &[u8]) -> Option<[u8; 32]> {
+        data.get(self.offset..self.offset + self.length)
+            .map(blake2b_256)
+    }
+}
+
+/// Secondary delegate which can unlock once the shadow cell is left untouched for `period`
+#[derive(Debug)]
+pub struct Heir {
+    pub period: Since,
+    pub delegate: Delegate,
+}
+
+impl Heir {
+    /// <relative since, u64 little endian 8 bytes><delegate entry>
+    fn unpack(value: &[u8]) -> Result<Heir, ShadowLockError> {
+        if value.len() < 8 {
+            return Err(ShadowLockError::Encoding);
+        }
+        let period = unpack_since(&value[0..8], false)?;
+        let (delegate, size) = Delegate::unpack(&value[8..])?;
+        if 8 + size != value.len() {
+            return Err(ShadowLockError::Encoding);
+        }
+        Ok(Heir { period, delegate })
+    }
+}
+
+/// Payment of `amount` to the `beneficiary` lock, in CKBytes or in the UDT of `udt_type_hash`
+#[derive(Debug)]
+pub struct Payment {
+    pub beneficiary: [u8; 32],
+    pub amount: u128,
+    pub udt_type_hash: Option<[u8; 32]>,
+}
+
+impl Payment {
+    /// <beneficiary lock hash, 32 bytes><amount, u128 little endian 16 bytes>[<udt type hash, 32 bytes>]
+    fn unpack(value: &[u8]) -> Result<Payment, ShadowLockError> {
+        let udt_type_hash = match value.len() {
+            48 => None,
+            80 => Some(value[48..80].try_into().unwrap()),
+            _ => return Err(ShadowLockError::Encoding),
+        };
+        Ok(Payment {
+            beneficiary: value[0..32].try_into().unwrap(),
+            amount: u128::from_le_bytes(value[32..48].try_into().unwrap()),
+            udt_type_hash,
+        })
+    }
+
+    /// Amount the output at `index` pays to the beneficiary
+    fn paid_by_output(&self, index: usize) -> Result<u128, ShadowLockError> {
+        if load_cell_lock_hash(index, Source::Output)? != self.beneficiary {
+            return Ok(0);
+        }
+        match self.udt_type_hash {
+            Some(udt_type_hash) => {
+                if load_cell_type_hash(index, Source::Output)? != Some(udt_type_hash) {
+                    return Ok(0);
+                }
+                let data = load_cell_data(index, Source::Output)?;
+                if data.len() < 16 {
+                    return Err(ShadowLockError::Encoding);
+                }
+                Ok(u128::from_le_bytes(data[0..16].try_into().unwrap()))
+            }
+            None => Ok(load_cell_capacity(index, Source::Output)? as u128),
+        }
+    }
+
+    /// Royalty: each shadow cell must be paid for, see `is_settled`
+    pub fn check_royalty(&self, shadow_in_input: &[usize]) -> Result<(), ShadowLockError> {
+        if !self.is_settled(shadow_in_input)? {
+            return Err(ShadowLockError::RoyaltyVerificationFailure);
+        }
+        Ok(())
+    }
+
+    /// Each shadow cell is paid for by the output at the same index as its input,
+    /// so one payment can not settle several cells, even of shadow locks with different args
+    pub fn is_settled(&self, shadow_in_input: &[usize]) -> Result<bool, ShadowLockError> {
+        for input_index in shadow_in_input.iter().copied() {
+            let tx_index = group_input_tx_index(input_index)?;
+            let paid = match self.paid_by_output(tx_index) {
+                Err(ShadowLockError::IndexOutOfBound) => 0,
+                paid => paid?,
+            };
+            debug!("input_index: {input_index}, tx_index: {tx_index}, paid: {paid}");
+            if paid < self.amount {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Index in transaction inputs of the group input at `group_index`
+fn group_input_tx_index(group_index: usize) -> Result<usize, ShadowLockError> {
+    let out_point = load_input_out_point(group_index, Source::GroupInput)?;
+    QueryIter::new(load_input_out_point, Source::Input)
+        .position(|input_out_point| input_out_point.as_slice() == out_point.as_slice())
+        .ok_or(ShadowLockError::ItemMissing)
+}
+
+/// Optional features carried after the delegate part of args,
+/// each one is encoded as <kind, 1 byte><length, 1 byte><value, length bytes>
+#[derive(Debug, Default)]
+pub struct Extensions {
+    pub expiry: Option<Expiry>,
+    /// owner lock hash, which can always unlock even without the delegate
+    pub owner: Option<[u8; 32]>,
+    /// type delegates presented in cell deps count as proof as well as inputs
+    pub cell_dep_proof: bool,
+    /// lock delegate which can be proven by executing it
+    pub exec_delegate: Option<ExecDelegate>,
+    /// shadow cell data starts with a remaining uses counter of the delegate
+    pub usage_limit: bool,
+    /// minimum relative since between two delegate unlocks
+    pub rate_limit: Option<Since>,
+    /// merkle root of lock hashes the shadow cell may move to
+    pub destination_root: Option<[u8; 32]>,
+    /// payment to a beneficiary required by every unlock
+    pub royalty: Option<Payment>,
+    /// delegate cells must be re-created unchanged
+    pub preserve_delegate: bool,
+    /// delegate cells must be consumed, one-shot keys
+    pub burn_delegate: bool,
+    /// restricted delegate data hash only covers a slice of data
+    pub data_slice: Option<DataSlice>,
+    /// earliest chain time the delegate can unlock at, proven by an input header
+    pub not_before: Option<Since>,
+    /// key which must sign the shadow cells for delegate unlocks
+    pub co_signer: Option<Auth>,
+    /// type hash of the registry cell listing revoked delegates
+    pub revocation_registry: Option<[u8; 32]>,
+    /// type hash of the guardian cell which can freeze every unlock
+    pub guardian: Option<[u8; 32]>,
+    /// anyone paying the price to the seller can unlock
+    pub sale: Option<Payment>,
+    /// delegate unlocks must re-create the cell under the return lease lock,
+    /// which carries the lease end as expiry and returns to the lessor as fallback owner
+    pub lease: Option<Expiry>,
+    /// dead man's switch, the heir takes over once the cell is left untouched
+    pub heir: Option<Heir>,
+}
+
+impl Extensions {
+    fn unpack(mut args: &[u8]) -> Result<Extensions, ShadowLockError> {
+        let mut extensions = Extensions::default();
+        while !args.is_empty() {
+            if args.len() < 2 || args.len() < 2 + args[1] as usize {
+                return Err(ShadowLockError::LengthNotEnough);
+            }
+            let (kind, value) = (args[0], &args[2..2 + args[1] as usize]);
+            match kind {
+                EXTENSION_EXPIRY if extensions.expiry.is_none() => {
+                    extensions.expiry = Some(Expiry::unpack(value)?)
+                }
+                EXTENSION_OWNER if extensions.owner.is_none() => {
+                    extensions.owner =
+                        Some(value.try_into().map_err(|_| ShadowLockError::Encoding)?)
+                }
+                EXTENSION_CELL_DEP_PROOF if !extensions.cell_dep_proof && value.is_empty() => {
+                    extensions.cell_dep_proof = true
+                }
+                EXTENSION_EXEC_DELEGATE if extensions.exec_delegate.is_none() => {
+                    extensions.exec_delegate = Some(ExecDelegate::unpack(value)?)
+                }
+                EXTENSION_USAGE_LIMIT if !extensions.usage_limit && value.is_empty() => {
+                    extensions.usage_limit = true
+                }
+                EXTENSION_RATE_LIMIT if extensions.rate_limit.is_none() => {
+                    extensions.rate_limit = Some(unpack_since(value, false)?)
+                }
+                EXTENSION_DESTINATION_ALLOWLIST if extensions.destination_root.is_none() => {
+                    extensions.destination_root =
+                        Some(value.try_into().map_err(|_| ShadowLockError::Encoding)?)
+                }
+                EXTENSION_ROYALTY if extensions.royalty.is_none() => {
+                    extensions.royalty = Some(Payment::unpack(value)?)
+                }
+                EXTENSION_PRESERVE_DELEGATE
+                    if !extensions.preserve_delegate && value.is_empty() =>
+                {
+                    extensions.preserve_delegate = true
+                }
+                EXTENSION_BURN_DELEGATE if !extensions.burn_delegate && value.is_empty() => {
+                    extensions.burn_delegate = true
+                }
+                EXTENSION_DELEGATE_DATA_SLICE if extensions.data_slice.is_none() => {
+                    extensions.data_slice = Some(DataSlice::unpack(value)?)
+                }
+                EXTENSION_NOT_BEFORE if extensions.not_before.is_none() => {
+                    extensions.not_before = Some(unpack_any_since(value, true)?)
+                }
+                // a header only proves the input was created before it, not that the cell
+                // is spent before it, so a deadline can not be enforced
+                EXTENSION_NOT_AFTER => return Err(ShadowLockError::UnsupportedArgs),
+                EXTENSION_CO_SIGNER if extensions.co_signer.is_none() => {
+                    extensions.co_signer = Some(Auth::unpack(value)?)
+                }
+                EXTENSION_REVOCATION_REGISTRY if extensions.revocation_registry.is_none() => {
+                    extensions.revocation_registry =
+                        Some(value.try_into().map_err(|_| ShadowLockError::Encoding)?)
+                }
+                EXTENSION_GUARDIAN if extensions.guardian.is_none() => {
+                    extensions.guardian =
+                        Some(value.try_into().map_err(|_| ShadowLockError::Encoding)?)
+                }
+                EXTENSION_SALE if extensions.sale.is_none() => {
+                    extensions.sale = Some(Payment::unpack(value)?)
+                }
+                EXTENSION_LEASE if extensions.lease.is_none() => {
+                    extensions.lease = Some(Expiry::unpack(value)?)
+                }
+                EXTENSION_HEIR if extensions.heir.is_none() => {
+                    extensions.heir = Some(Heir::unpack(value)?)
+                }
+                // duplicated extension
+                EXTENSION_EXPIRY
+                | EXTENSION_OWNER
+                | EXTENSION_CELL_DEP_PROOF
+                | EXTENSION_EXEC_DELEGATE
+                | EXTENSION_USAGE_LIMIT
+                | EXTENSION_RATE_LIMIT
+                | EXTENSION_DESTINATION_ALLOWLIST
+                | EXTENSION_ROYALTY
+                | EXTENSION_PRESERVE_DELEGATE
+                | EXTENSION_BURN_DELEGATE
+                | EXTENSION_DELEGATE_DATA_SLICE
+                | EXTENSION_NOT_BEFORE
+                | EXTENSION_CO_SIGNER
+                | EXTENSION_REVOCATION_REGISTRY
+                | EXTENSION_GUARDIAN
+                | EXTENSION_SALE
+                | EXTENSION_LEASE
+                | EXTENSION_HEIR => return Err(ShadowLockError::Encoding),
+                _ => return Err(ShadowLockError::UnsupportedArgs),
+            }
+            args = &args[2 + value.len()..];
+        }
+        // a key cannot be both preserved and burned, and a key in cell deps is never burned
+        if extensions.burn_delegate && (extensions.preserve_delegate || extensions.cell_dep_proof) {
+            return Err(ShadowLockError::Encoding);
+        }
+        // a returned lease expires with the lease, it can not carry another expiry
+        if extensions.lease.is_some()
+            && extensions.expiry.is_some()
+            && extensions.lease != extensions.expiry
+        {
+            return Err(ShadowLockError::Encoding);
+        }
+        Ok(extensions)
+    }
 }
 
 pub fn unpack_script_args(args: &[u8]) -> Result<UnpackedShadowlockArgs, ShadowLockError> {
-    if args.len() < 33 {
+    if args.is_empty() {
         return Err(ShadowLockError::LengthNotEnough);
     }
-    let flags = FeatureFlags::unpack(args[0]);
-    let ref_hash: [u8; 32] = args[1..33].try_into().unwrap();
-    let data_hash: Option<[u8; 32]> = if flags.restrict_delegate_data {
-        if args.len() < 65 {
+    let args = if args[0] & ARGS_VERSION_HEADER != 0 {
+        if args[0] & !ARGS_VERSION_HEADER != ARGS_VERSION {
+            return Err(ShadowLockError::UnsupportedArgs);
+        }
+        if args.len() < 2 {
             return Err(ShadowLockError::LengthNotEnough);
         }
-        Some(args[33..65].try_into().unwrap())
+        &args[1..]
     } else {
-        None
+        // version 0 args keep their original meaning
+        if args[0] & !V0_FLAG_BITS != 0 {
+            return Err(ShadowLockError::UnsupportedArgs);
+        }
+        args
+    };
+    let flags = FeatureFlags::unpack(args[0])?;
+
+    let (delegates, threshold, offset) = if flags.delegate_list {
+        // <threshold, 1 byte><count, 1 byte><count * delegate entry>
+        if args.len() < 3 {
+            return Err(ShadowLockError::LengthNotEnough);
+        }
+        let threshold = args[1] as usize;
+        let count = args[2] as usize;
+        let mut offset = 3;
+        let mut delegates: Vec<Delegate> = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (delegate, size) = Delegate::unpack(&args[offset..])?;
+            // delegates must be distinct, otherwise one cell could be counted twice
+            if delegates.contains(&delegate) {
+                return Err(ShadowLockError::Encoding);
+            }
+            delegates.push(delegate);
+            offset += size;
+        }
+        if threshold == 0 || threshold > count {
+            return Err(ShadowLockError::Encoding);
+        }
+        (delegates, threshold, offset)
+    } else {
+        if args.len() < 33 {
+            return Err(ShadowLockError::LengthNotEnough);
+        }
+        let delegate = Delegate::Hash(flags.get_delegate_target(), args[1..33].try_into().unwrap());
+        (vec![delegate], 1, 33)
     };
 
+    let (data_hash, offset) = if flags.restrict_delegate_data {
+        if args.len() < offset + 32 {
+            return Err(ShadowLockError::LengthNotEnough);
+        }
+        (
+            Some(args[offset..offset + 32].try_into().unwrap()),
+            offset + 32,
+        )
+    } else {
+        (None, offset)
+    };
+
+    // extensions run to the end of args, nothing else may follow
+    let extensions = if flags.extensions {
+        Extensions::unpack(&args[offset..])?
+    } else if args.len() > offset {
+        return Err(ShadowLockError::Encoding);
+    } else {
+        Extensions::default()
+    };
+
+    // the slice only narrows down restricted delegate data
+    if extensions.data_slice.is_some() && data_hash.is_none() {
+        return Err(ShadowLockError::Encoding);
+    }
+
+    // the buyer could never take a cell which is forbidden to trade
+    if extensions.sale.is_some() && flags.forbid_trade {
+        return Err(ShadowLockError::Encoding);
+    }
+
+    // the sale price and the royalty would both claim the output paired with each cell
+    if extensions.sale.is_some() && extensions.royalty.is_some() {
+        return Err(ShadowLockError::Encoding);
+    }
+
+    // the executed lock must be one of the lock delegates
+    if let Some(exec_delegate) = &extensions.exec_delegate {
+        let exec_delegate = Delegate::Hash(LoadHashTarget::Lock, exec_delegate.script_hash());
+        if !delegates.contains(&exec_delegate) {
+            return Err(ShadowLockError::Encoding);
+        }
+        // the signature and the destination proofs both take the shadow cell witness lock
+        if extensions.destination_root.is_some() {
+            return Err(ShadowLockError::Encoding);
+        }
+    }
+
+    // the co-signature takes the shadow cell witness lock too,
+    // and only one of the signatures can be verified since exec never returns
+    if extensions.co_signer.is_some()
+        && (extensions.destination_root.is_some() || extensions.exec_delegate.is_some())
+    {
+        return Err(ShadowLockError::Encoding);
+    }
+
     Ok(UnpackedShadowlockArgs {
         flags,
-        ref_hash,
+        delegates,
+        threshold,
         data_hash,
+        extensions,
     })
 }
 
@@ -105,8 +735,8 @@ pub fn check_input_output_contain_same_cell(
         .enumerate()
         .filter(|(_, x)| x == &input_type_hash)
         .filter(|(tp, _)| {
-            let data_matches = !check_data || data_position.contains(&tp);
-            let lock_matches = !check_lock || lock_position.contains(&tp);
+            let data_matches = !check_data || data_position.contains(tp);
+            let lock_matches = !check_lock || lock_position.contains(tp);
             debug!("index: {tp}, data_matches: {data_matches}, lock_matches: {lock_matches}");
             data_matches && lock_matches
         })
@@ -119,15 +749,464 @@ pub fn check_input_output_contain_same_cell(
     Ok(found_same_cell)
 }
 
+/// Outputs reserved as successors of input cells, so that each input cell is
+/// re-created by its own output and one output can not stand for several of them.
+#[derive(Default)]
+pub struct Successors {
+    reserved: Vec<usize>,
+}
+
+impl Successors {
+    /// Reserve a free output re-creating the input cell: same type, and same data and lock
+    /// if checked, which also passes `matches`. Returns false if there is none.
+    pub fn reserve(
+        &mut self,
+        input_index: usize,
+        source: Source,
+        check_data: bool,
+        check_lock: bool,
+        matches: impl Fn(usize) -> bool,
+    ) -> Result<bool, ShadowLockError> {
+        let successor =
+            check_input_output_contain_same_cell(input_index, source, check_data, check_lock)?
+                .into_iter()
+                .find(|index| !self.reserved.contains(index) && matches(*index));
+        debug!("input_index: {input_index}, successor: {:?}", successor);
+        match successor {
+            Some(index) => {
+                self.reserved.push(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
 pub fn delegate_data_owner_check(
     delegate_data_hash: Option<[u8; 32]>,
+    data_slice: Option<DataSlice>,
     index: usize,
     source: Source,
 ) -> Result<bool, ShadowLockError> {
-    if delegate_data_hash.is_some() {
-        let cell_data_hash = load_cell_data_hash(index, source)?;
-        Ok(cell_data_hash == delegate_data_hash.unwrap())
+    if let Some(delegate_data_hash) = delegate_data_hash {
+        let cell_data_hash = match data_slice {
+            Some(data_slice) => data_slice.hash(&load_cell_data(index, source)?),
+            None => Some(load_cell_data_hash(index, source)?),
+        };
+        Ok(cell_data_hash == Some(delegate_data_hash))
     } else {
         Ok(true)
     }
 }
+
+/// Find all cells in `source` that act as `delegate`,
+/// with the optional delegate data restriction applied.
+pub fn find_delegate_inputs(
+    delegate: &Delegate,
+    delegate_data_hash: Option<[u8; 32]>,
+    data_slice: Option<DataSlice>,
+    source: Source,
+) -> Vec<usize> {
+    // data hash check if needed
+    let data_matches = |index| {
+        delegate_data_owner_check(delegate_data_hash, data_slice, index, source)
+            .is_ok_and(|check_result| check_result)
+    };
+
+    match delegate {
+        Delegate::Hash(target, ref_hash) => {
+            let load_hash: fn(usize, Source) -> Result<[u8; 32], SysError> = match target {
+                LoadHashTarget::Type => |index, source| {
+                    load_cell_type_hash(index, source)
+                        .map(|type_hash| type_hash.unwrap_or_default())
+                },
+                LoadHashTarget::Lock => load_cell_lock_hash,
+                // anyone knowing the data can create a cell holding it,
+                // so only data kept secret until the unlock is protected
+                LoadHashTarget::Data => load_cell_data_hash,
+            };
+
+            QueryIter::new(load_hash, source)
+                .enumerate()
+                .filter(|(index, hash)| hash == ref_hash && data_matches(*index))
+                .map(|(index, _)| index)
+                .collect()
+        }
+        Delegate::TypePattern(pattern) => QueryIter::new(load_cell_type, source)
+            .enumerate()
+            .filter(|(index, type_script)| {
+                type_script
+                    .as_ref()
+                    .is_some_and(|type_script| pattern.matches(type_script))
+                    && data_matches(*index)
+            })
+            .map(|(index, _)| index)
+            .collect(),
+        Delegate::LockPattern(pattern) => QueryIter::new(load_cell_lock, source)
+            .enumerate()
+            .filter(|(index, lock_script)| pattern.matches(lock_script) && data_matches(*index))
+            .map(|(index, _)| index)
+            .collect(),
+        // data carries the amount, so the data restriction does not apply
+        Delegate::TokenBalance(type_hash, amount) => {
+            let mut balance: u128 = 0;
+            let mut cells = Vec::new();
+            for (index, cell_type_hash) in QueryIter::new(load_cell_type_hash, source).enumerate() {
+                if cell_type_hash != Some(*type_hash) {
+                    continue;
+                }
+                if let Some(cell_amount) = load_cell_data(index, source)
+                    .ok()
+                    .and_then(|data| data.get(0..16).map(|x| x.try_into().unwrap()))
+                {
+                    balance = balance.saturating_add(u128::from_le_bytes(cell_amount));
+                    cells.push(index);
+                }
+            }
+            debug!("token balance: {balance}, required: {amount}");
+            if balance >= *amount {
+                cells
+            } else {
+                vec![]
+            }
+        }
+    }
+}
+
+/// Find all cells proving `delegate` is held, as (index, source) pairs.
+///
+/// Delegates are looked up in inputs, type delegates are also looked up in cell deps
+/// if cell dep proof is enabled. Anyone can reference a cell in cell deps, so the holder
+/// must prove ownership by presenting an input with the same lock as the delegate cell.
+/// Lock or data hashes can be forged by anyone, so they always need to be presented in inputs.
+pub fn find_delegate_cells(
+    args: &UnpackedShadowlockArgs,
+    delegate: &Delegate,
+) -> Vec<(usize, Source)> {
+    let mut cells = find_delegate_inputs(
+        delegate,
+        args.data_hash,
+        args.extensions.data_slice,
+        Source::Input,
+    )
+    .into_iter()
+    .map(|index| (index, Source::Input))
+    .collect::<Vec<_>>();
+    let type_delegate = matches!(
+        delegate,
+        Delegate::Hash(LoadHashTarget::Type, _) | Delegate::TypePattern(_)
+    );
+    if args.extensions.cell_dep_proof && type_delegate {
+        cells.extend(
+            find_delegate_inputs(
+                delegate,
+                args.data_hash,
+                args.extensions.data_slice,
+                Source::CellDep,
+            )
+            .into_iter()
+            .filter(|index| {
+                load_cell_lock_hash(*index, Source::CellDep).is_ok_and(lock_hash_in_inputs)
+            })
+            .map(|index| (index, Source::CellDep)),
+        );
+    }
+    cells
+}
+
+/// Count the delegates present by different cells, `delegate_cells` being the cells
+/// proving each delegate. One cell can match several delegates, e.g. a lock delegate and a
+/// type delegate, or overlapping patterns, but it only counts for one of them.
+pub fn count_present_delegates(delegate_cells: &[Vec<(usize, Source)>]) -> usize {
+    // cell assigned to each delegate
+    let mut assigned: Vec<Option<(usize, Source)>> = vec![None; delegate_cells.len()];
+    let mut count = 0;
+    for delegate in 0..delegate_cells.len() {
+        let mut visited = Vec::new();
+        if assign_delegate_cell(delegate, delegate_cells, &mut assigned, &mut visited) {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Find a free cell for `delegate`, moving the delegates holding its cells to other cells if needed
+fn assign_delegate_cell(
+    delegate: usize,
+    delegate_cells: &[Vec<(usize, Source)>],
+    assigned: &mut Vec<Option<(usize, Source)>>,
+    visited: &mut Vec<(usize, Source)>,
+) -> bool {
+    for cell in delegate_cells[delegate].iter().copied() {
+        if visited.contains(&cell) {
+            continue;
+        }
+        visited.push(cell);
+        let holder = assigned.iter().position(|assigned| *assigned == Some(cell));
+        if holder
+            .is_none_or(|holder| assign_delegate_cell(holder, delegate_cells, assigned, visited))
+        {
+            assigned[delegate] = Some(cell);
+            return true;
+        }
+    }
+    false
+}
+
+/// Cells proving `delegate` is held, leaving out the cells revoked by the registry
+pub fn find_present_delegate_cells(
+    args: &UnpackedShadowlockArgs,
+    delegate: &Delegate,
+    revocation_registry: Option<&RevocationRegistry>,
+) -> Vec<(usize, Source)> {
+    find_delegate_cells(args, delegate)
+        .into_iter()
+        .filter(|(index, source)| {
+            revocation_registry
+                .is_none_or(|registry| !registry.is_revoked(delegate, *index, *source))
+        })
+        .collect()
+}
+
+/// Lock hashes the delegates and the heir are held by: the delegate lock hash itself for lock
+/// delegates, or the locks of the cells carrying type or data delegates. Revoked ones are left out.
+pub fn get_delegate_lock_hashes(
+    args: &UnpackedShadowlockArgs,
+    revocation_registry: Option<&RevocationRegistry>,
+) -> Result<Vec<[u8; 32]>, ShadowLockError> {
+    let mut lock_hashes = Vec::new();
+    let heir = args.extensions.heir.as_ref().map(|heir| &heir.delegate);
+    for delegate in args.delegates.iter().chain(heir) {
+        match delegate {
+            Delegate::Hash(LoadHashTarget::Lock, ref_hash) => {
+                if revocation_registry.is_none_or(|registry| !registry.contains(ref_hash)) {
+                    lock_hashes.push(*ref_hash)
+                }
+            }
+            _ => {
+                for (index, source) in
+                    find_present_delegate_cells(args, delegate, revocation_registry)
+                {
+                    lock_hashes.push(load_cell_lock_hash(index, source)?);
+                }
+            }
+        }
+    }
+    Ok(lock_hashes)
+}
+
+/// Every input carrying a type or data delegate must be re-created in outputs
+/// with the same lock, type and data, each one by its own output.
+pub fn check_delegate_preserved(args: &UnpackedShadowlockArgs) -> Result<(), ShadowLockError> {
+    let mut successors = Successors::default();
+    for delegate in args.delegates.iter().filter(|d| d.is_carried_by_cell()) {
+        for (index, source) in find_delegate_cells(args, delegate) {
+            // cells in cell deps are not consumed
+            if source != Source::Input {
+                continue;
+            }
+            if !successors.reserve(index, source, true, true, |_| true)? {
+                return Err(ShadowLockError::DelegatePreservationFailure);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// No input carrying a type or data delegate may be re-created in outputs,
+/// a cell with the same type for type delegates, or the same data for data delegates.
+pub fn check_delegate_burned(args: &UnpackedShadowlockArgs) -> Result<(), ShadowLockError> {
+    for delegate in args.delegates.iter().filter(|d| d.is_carried_by_cell()) {
+        let check_data = matches!(delegate, Delegate::Hash(LoadHashTarget::Data, _));
+        for (index, source) in find_delegate_cells(args, delegate) {
+            if !check_input_output_contain_same_cell(index, source, check_data, false)?.is_empty() {
+                return Err(ShadowLockError::DelegateBurnVerificationFailure);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Code hash of the Type ID type script, with hash type `type`
+const TYPE_ID_CODE_HASH: [u8; 32] = *b"\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0TYPE_ID";
+
+/// Whether the cell has a Type ID type script, so that no other cell can have the same type
+fn has_type_id(index: usize, source: Source) -> Result<bool, ShadowLockError> {
+    Ok(load_cell_type(index, source)?.is_some_and(|type_script| {
+        type_script.code_hash().as_slice() == TYPE_ID_CODE_HASH
+            && type_script.hash_type().as_slice()[0] == ScriptHashType::Type as u8
+    }))
+}
+
+/// Revoked delegate hashes, from the data of the registry cell in cell deps.
+/// Registry data is a list of 32 bytes hashes sorted in strictly ascending order.
+pub struct RevocationRegistry {
+    list: Vec<u8>,
+}
+
+impl RevocationRegistry {
+    /// The registry cell must be in cell deps, otherwise revocation could be bypassed by
+    /// leaving it out, with a Type ID type script, otherwise anyone could reference a copy
+    /// with an empty list
+    pub fn load(type_hash: [u8; 32]) -> Result<RevocationRegistry, ShadowLockError> {
+        let registry = QueryIter::new(load_cell_type_hash, Source::CellDep)
+            .position(|cell_type_hash| cell_type_hash == Some(type_hash))
+            .ok_or(ShadowLockError::RevocationVerificationFailure)?;
+        if !has_type_id(registry, Source::CellDep)? {
+            return Err(ShadowLockError::RevocationVerificationFailure);
+        }
+        let list = load_cell_data(registry, Source::CellDep)?;
+        if list.len() % 32 != 0 {
+            return Err(ShadowLockError::RevocationVerificationFailure);
+        }
+        // an unsorted list would silently fail to revoke in the binary search
+        let unsorted = (32..list.len())
+            .step_by(32)
+            .any(|offset| list[offset - 32..offset] >= list[offset..offset + 32]);
+        if unsorted {
+            return Err(ShadowLockError::RevocationVerificationFailure);
+        }
+        Ok(RevocationRegistry { list })
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        let (mut low, mut high) = (0, self.list.len() / 32);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.list[mid * 32..mid * 32 + 32].cmp(hash) {
+                Ordering::Equal => return true,
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+            }
+        }
+        false
+    }
+
+    /// A delegate cell is revoked by the hash it is matched by: the lock, type or data hash
+    /// for hash delegates, the type hash for type patterns and token balances, the lock hash
+    /// for lock patterns
+    pub fn is_revoked(&self, delegate: &Delegate, index: usize, source: Source) -> bool {
+        let hash = match delegate {
+            Delegate::Hash(LoadHashTarget::Lock, _) | Delegate::LockPattern(_) => {
+                load_cell_lock_hash(index, source).ok()
+            }
+            Delegate::Hash(LoadHashTarget::Data, _) => load_cell_data_hash(index, source).ok(),
+            Delegate::Hash(LoadHashTarget::Type, _)
+            | Delegate::TypePattern(_)
+            | Delegate::TokenBalance(..) => load_cell_type_hash(index, source).ok().flatten(),
+        };
+        hash.is_some_and(|hash| self.contains(&hash))
+    }
+}
+
+/// The guardian cell must be in cell deps, with a Type ID type script so that no one else can
+/// create a cell of the same type, and its first data byte must be zero.
+/// A missing guardian is treated as frozen, otherwise the freeze could be bypassed by
+/// leaving it out.
+pub fn check_guardian(type_hash: [u8; 32]) -> Result<(), ShadowLockError> {
+    let guardian = QueryIter::new(load_cell_type_hash, Source::CellDep)
+        .position(|cell_type_hash| cell_type_hash == Some(type_hash))
+        .ok_or(ShadowLockError::Frozen)?;
+    if !has_type_id(guardian, Source::CellDep)? {
+        return Err(ShadowLockError::Frozen);
+    }
+    let data = load_cell_data(guardian, Source::CellDep)?;
+    debug!("guardian cell dep: {guardian}, data: {:?}", data);
+    if data.first().is_some_and(|frozen| *frozen != 0) {
+        return Err(ShadowLockError::Frozen);
+    }
+    Ok(())
+}
+
+/// Args of the return lease lock: the same args with the lease as expiry.
+/// Args already carrying it are returned as is, so the renter keeps the cell
+/// under the same lock until the lease ends.
+pub fn lease_return_args(args: &[u8], extensions: &Extensions) -> Vec<u8> {
+    let mut return_args = args.to_vec();
+    if let (Some(lease), None) = (&extensions.lease, &extensions.expiry) {
+        // extensions are the last part of args
+        return_args.extend([EXTENSION_EXPIRY, 40]);
+        return_args.extend(lease.since.as_u64().to_le_bytes());
+        return_args.extend(lease.fallback_owner);
+    }
+    return_args
+}
+
+/// Every shadow cell must be re-created with the same type, data and capacity under
+/// `return_lock_hash`, each one by its own output.
+pub fn check_lease_return(
+    shadow_in_input: &[usize],
+    return_lock_hash: [u8; 32],
+) -> Result<(), ShadowLockError> {
+    let mut successors = Successors::default();
+    for input_index in shadow_in_input.iter().copied() {
+        let capacity = load_cell_capacity(input_index, Source::GroupInput)?;
+        let returned =
+            successors.reserve(input_index, Source::GroupInput, true, false, |index| {
+                load_cell_lock_hash(index, Source::Output)
+                    .is_ok_and(|lock_hash| lock_hash == return_lock_hash)
+                    && load_cell_capacity(index, Source::Output)
+                        .is_ok_and(|output| output == capacity)
+            })?;
+        if !returned {
+            return Err(ShadowLockError::LeaseVerificationFailure);
+        }
+    }
+    Ok(())
+}
+
+/// Every shadow cell must be re-created with the same lock, type and capacity, each one by its
+/// own output, so that it is still under the expiring lock when the fallback owner reclaims it.
+pub fn check_expiry_kept(shadow_in_input: &[usize]) -> Result<(), ShadowLockError> {
+    let mut successors = Successors::default();
+    for input_index in shadow_in_input.iter().copied() {
+        let capacity = load_cell_capacity(input_index, Source::GroupInput)?;
+        let kept = successors.reserve(input_index, Source::GroupInput, false, true, |index| {
+            load_cell_capacity(index, Source::Output).is_ok_and(|output| output == capacity)
+        })?;
+        if !kept {
+            return Err(ShadowLockError::ExpiryVerificationFailure);
+        }
+    }
+    Ok(())
+}
+
+pub fn lock_hash_in_inputs(lock_hash: [u8; 32]) -> bool {
+    QueryIter::new(load_cell_lock_hash, Source::Input)
+        .any(|input_lock_hash| input_lock_hash == lock_hash)
+}
+
+/// Every unlock with a remaining uses counter above zero must re-create the shadow cell
+/// under the same lock and type with the same capacity, with the counter decremented and the
+/// rest of data unchanged.
+/// The counter is the first 8 bytes of the shadow cell data, u64 little endian.
+/// Once the counter hits zero the cell can be released.
+pub fn check_usage_limit(shadow_in_input: &[usize]) -> Result<(), ShadowLockError> {
+    let mut successors = Successors::default();
+    for input_index in shadow_in_input.iter().copied() {
+        let data = load_cell_data(input_index, Source::GroupInput)?;
+        if data.len() < 8 {
+            return Err(ShadowLockError::UsageLimitVerificationFailure);
+        }
+        let remaining_uses = u64::from_le_bytes(data[0..8].try_into().unwrap());
+        debug!("input_index: {input_index}, remaining uses: {remaining_uses}");
+        if remaining_uses == 0 {
+            continue;
+        }
+
+        let capacity = load_cell_capacity(input_index, Source::GroupInput)?;
+        let mut successor_data = data;
+        successor_data[0..8].copy_from_slice(&(remaining_uses - 1).to_le_bytes());
+        let recreated =
+            successors.reserve(input_index, Source::GroupInput, false, true, |index| {
+                load_cell_data(index, Source::Output)
+                    .is_ok_and(|output_data| output_data == successor_data)
+                    && load_cell_capacity(index, Source::Output)
+                        .is_ok_and(|output| output == capacity)
+            })?;
+        if !recreated {
+            return Err(ShadowLockError::UsageLimitVerificationFailure);
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,25 @@
 use crate::Loader;
+use ckb_system_scripts::BUNDLED_CELL;
 use ckb_testtool::builtin::ALWAYS_SUCCESS;
-use ckb_testtool::ckb_types::{bytes::Bytes, core::TransactionBuilder, packed::*, prelude::*};
+use ckb_testtool::ckb_crypto::secp::{Generator, Privkey};
+use ckb_testtool::ckb_error::Error;
+use ckb_testtool::ckb_hash::{blake2b_256, new_blake2b};
+use ckb_testtool::ckb_types::{
+    bytes::Bytes,
+    core::{
+        EpochNumberWithFraction, HeaderBuilder, HeaderView, ScriptHashType, TransactionBuilder,
+        TransactionView,
+    },
+    packed::*,
+    prelude::*,
+};
 use ckb_testtool::context::Context;
 
 // Include your tests here
 // See https://github.com/xxuejie/ckb-native-build-sample/blob/main/tests/src/tests.rs for more examples
 
+const MAX_CYCLES: u64 = 10_000_000;
+
 fn build_lock_args(mode: u8, script_hash: [u8; 32]) -> Bytes {
     Bytes::copy_from_slice(
         &[mode]
@@ -16,6 +30,371 @@ fn build_lock_args(mode: u8, script_hash: [u8; 32]) -> Bytes {
     )
 }
 
+fn assert_script_error(err: Error, err_code: i8) {
+    let error_string = err.to_string();
+    assert!(
+        error_string.contains(format!("error code {} ", err_code).as_str()),
+        "error_string: {}, expected_error_code: {}",
+        error_string,
+        err_code
+    );
+}
+
+fn hash(script: &Script) -> [u8; 32] {
+    script.calc_script_hash().unpack().0
+}
+
+fn cell(capacity: u64, lock: &Script) -> CellOutput {
+    CellOutput::new_builder()
+        .capacity(capacity.pack())
+        .lock(lock.clone())
+        .build()
+}
+
+fn typed_cell(capacity: u64, lock: &Script, type_script: &Script) -> CellOutput {
+    CellOutput::new_builder()
+        .capacity(capacity.pack())
+        .lock(lock.clone())
+        .type_(Some(type_script.clone()).pack())
+        .build()
+}
+
+/// Type ID type script with `[seed; 32]` as args, no other cell can be created with this type
+fn type_id_script(seed: u8) -> Script {
+    let mut code_hash = [0u8; 32];
+    code_hash[25..].copy_from_slice(b"TYPE_ID");
+    Script::new_builder()
+        .code_hash(code_hash.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(vec![seed; 32]).pack())
+        .build()
+}
+
+/// <code hash, 32 bytes><hash type, 1 byte><args> of `script`
+fn script_value(script: &Script) -> Vec<u8> {
+    let mut value = script.code_hash().as_slice().to_vec();
+    value.extend(script.hash_type().as_slice());
+    value.extend(script.args().raw_data());
+    value
+}
+
+// delegate list entry of kind 0 = lock, 1 = type, 2 = data
+fn hash_entry(kind: u8, ref_hash: [u8; 32]) -> Vec<u8> {
+    let mut entry = vec![kind];
+    entry.extend(ref_hash);
+    entry
+}
+
+// delegate list entry of kind 3 = type pattern with the args prefix, 4 = lock pattern without
+fn pattern_entry(kind: u8, script: &Script, args_prefix: Option<&[u8]>) -> Vec<u8> {
+    let mut entry = vec![kind];
+    entry.extend(script.code_hash().as_slice());
+    entry.extend(script.hash_type().as_slice());
+    if let Some(args_prefix) = args_prefix {
+        entry.push(args_prefix.len() as u8);
+        entry.extend(args_prefix);
+    }
+    entry
+}
+
+/// Shadow lock args: mode flags, the delegate or the delegate list, the restricted delegate
+/// data hash and extensions, the matching mode flag is set along with each part
+#[derive(Clone)]
+struct LockArgs {
+    mode: u8,
+    delegate: Vec<u8>,
+    data_hash: Option<[u8; 32]>,
+    extensions: Vec<u8>,
+}
+
+impl LockArgs {
+    fn new(mode: u8, ref_hash: [u8; 32]) -> Self {
+        LockArgs {
+            mode,
+            delegate: ref_hash.to_vec(),
+            data_hash: None,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// `threshold` of the delegate list `entries` must be presented
+    fn list(mode: u8, threshold: u8, entries: &[Vec<u8>]) -> Self {
+        let mut delegate = vec![threshold, entries.len() as u8];
+        entries.iter().for_each(|entry| delegate.extend(entry));
+        LockArgs {
+            mode: mode | 0b00100000,
+            delegate,
+            data_hash: None,
+            extensions: Vec::new(),
+        }
+    }
+
+    fn data_hash(mut self, data_hash: [u8; 32]) -> Self {
+        self.mode |= 0b00001000;
+        self.data_hash = Some(data_hash);
+        self
+    }
+
+    fn extension(mut self, kind: u8, value: &[u8]) -> Self {
+        self.mode |= 0b01000000;
+        self.extensions.extend([kind, value.len() as u8]);
+        self.extensions.extend(value);
+        self
+    }
+
+    fn build(&self) -> Bytes {
+        // flags of version 1 need the version header
+        let mut args = if self.mode & 0b01110000 != 0 {
+            vec![0x81, self.mode]
+        } else {
+            vec![self.mode]
+        };
+        args.extend(&self.delegate);
+        args.extend(self.data_hash.iter().flatten());
+        args.extend(&self.extensions);
+        Bytes::from(args)
+    }
+}
+
+/// Transaction under test, with the shadow lock and always success deployed.
+/// Other scripts of the transaction are always success scripts told apart by their args.
+struct ShadowLockTest {
+    context: Context,
+    shadow_lock_out_point: OutPoint,
+    always_success_out_point: OutPoint,
+    inputs: Vec<CellInput>,
+    outputs: Vec<CellOutput>,
+    outputs_data: Vec<Bytes>,
+    cell_deps: Vec<CellDep>,
+    header_deps: Vec<Byte32>,
+    witnesses: Vec<(usize, Bytes)>,
+    signers: Vec<(usize, Privkey)>,
+}
+
+impl ShadowLockTest {
+    fn new() -> Self {
+        let mut context = Context::default();
+        let shadow_lock_out_point =
+            context.deploy_cell(Loader::default().load_binary("shadow-lock"));
+        let always_success_out_point = context.deploy_cell(ALWAYS_SUCCESS.clone());
+        ShadowLockTest {
+            context,
+            shadow_lock_out_point,
+            always_success_out_point,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            outputs_data: Vec::new(),
+            cell_deps: Vec::new(),
+            header_deps: Vec::new(),
+            witnesses: Vec::new(),
+            signers: Vec::new(),
+        }
+    }
+
+    fn script_with_args(&mut self, args: &[u8]) -> Script {
+        self.context
+            .build_script(&self.always_success_out_point, Bytes::copy_from_slice(args))
+            .expect("failed to build script")
+    }
+
+    /// Always success script with `[seed; 32]` as args, as a lock or a type
+    fn script(&mut self, seed: u8) -> Script {
+        self.script_with_args(&[seed; 32])
+    }
+
+    fn shadow_lock(&mut self, args: Bytes) -> Script {
+        self.context
+            .build_script(&self.shadow_lock_out_point, args)
+            .expect("script")
+    }
+
+    fn input(&mut self, cell: CellOutput, data: &[u8]) -> OutPoint {
+        self.input_with_since(cell, data, 0)
+    }
+
+    fn input_with_since(&mut self, cell: CellOutput, data: &[u8], since: u64) -> OutPoint {
+        let out_point = self.context.create_cell(cell, Bytes::copy_from_slice(data));
+        self.inputs.push(
+            CellInput::new_builder()
+                .previous_output(out_point.clone())
+                .since(since.pack())
+                .build(),
+        );
+        out_point
+    }
+
+    fn output(&mut self, cell: CellOutput, data: &[u8]) {
+        self.outputs.push(cell);
+        self.outputs_data.push(Bytes::copy_from_slice(data));
+    }
+
+    /// Plain cell of `key` lock presented in inputs, and handed back to it in outputs
+    fn key(&mut self, key: &Script) -> OutPoint {
+        self.output(cell(1000, key), &[]);
+        self.input(cell(1000, key), &[])
+    }
+
+    fn cell_dep(&mut self, cell: CellOutput, data: &[u8]) {
+        let out_point = self.context.create_cell(cell, Bytes::copy_from_slice(data));
+        self.cell_deps
+            .push(CellDep::new_builder().out_point(out_point).build());
+    }
+
+    /// Deploy `bin` in cell deps, for code which is not a script of the transaction
+    fn code_dep(&mut self, bin: Bytes) -> OutPoint {
+        let out_point = self.context.deploy_cell(bin);
+        self.cell_deps
+            .push(CellDep::new_builder().out_point(out_point.clone()).build());
+        out_point
+    }
+
+    /// Deploy ckb-auth in cell deps, and return the value verifying the key of `privkey`
+    /// through it: <ckb-auth code hash><hash type><pubkey hash>
+    fn auth(&mut self, privkey: &Privkey) -> Vec<u8> {
+        let bin = Loader::default().load_binary("ckb-auth-stub");
+        let mut value = blake2b_256(&bin).to_vec();
+        value.push(ScriptHashType::Data1.into());
+        value.extend(pubkey_hash(privkey));
+        self.code_dep(bin);
+        value
+    }
+
+    /// Deploy the secp256k1 blake160 sighash all lock and its data in cell deps
+    fn secp256k1_deps(&mut self) {
+        for name in [
+            "specs/cells/secp256k1_blake160_sighash_all",
+            "specs/cells/secp256k1_data",
+        ] {
+            let bin = BUNDLED_CELL.get(name).expect("secp256k1 cell");
+            self.code_dep(bin.to_vec().into());
+        }
+    }
+
+    /// Sign the shadow cell at `index` as `signature` tells, `privkey` being the expected key
+    fn sign_shadow(&mut self, index: usize, privkey: &Privkey, signature: ShadowSignature) {
+        match signature {
+            ShadowSignature::Key => self.sign(index, privkey),
+            ShadowSignature::OtherKey => self.sign(index, &Generator::random_privkey()),
+            ShadowSignature::Missing => {}
+        }
+    }
+
+    /// Block header at `number`, `timestamp` in milliseconds
+    fn header(&mut self, number: u64, timestamp: u64) -> HeaderView {
+        let header = HeaderBuilder::default()
+            .number(number.pack())
+            .epoch(EpochNumberWithFraction::new(0, number, 1000).pack())
+            .timestamp(timestamp.pack())
+            .build();
+        self.context.insert_header(header.clone());
+        header
+    }
+
+    fn header_dep(&mut self, header: &HeaderView) {
+        self.header_deps.push(header.hash());
+    }
+
+    fn witness(&mut self, index: usize, witness: Bytes) {
+        self.witnesses.push((index, witness));
+    }
+
+    /// Sign the single input group at `index` once the transaction is built
+    fn sign(&mut self, index: usize, privkey: &Privkey) {
+        self.signers.push((index, privkey.clone()));
+    }
+
+    fn build(&mut self) -> TransactionView {
+        let mut witnesses = vec![Bytes::new(); self.inputs.len()];
+        for (index, witness) in &self.witnesses {
+            witnesses[*index] = witness.clone();
+        }
+        let tx = TransactionBuilder::default()
+            .inputs(self.inputs.clone())
+            .outputs(self.outputs.clone())
+            .outputs_data(self.outputs_data.pack())
+            .cell_deps(self.cell_deps.clone())
+            .header_deps(self.header_deps.clone())
+            .witnesses(witnesses.pack())
+            .build();
+        let tx = self.context.complete_tx(tx);
+        self.signers
+            .iter()
+            .fold(tx, |tx, (index, privkey)| sign_group(tx, privkey, *index))
+    }
+
+    fn pass(mut self) {
+        let tx = self.build();
+        let cycles = self
+            .context
+            .verify_tx(&tx, MAX_CYCLES)
+            .expect("pass verification");
+        println!("consume cycles: {}", cycles);
+    }
+
+    fn fail(mut self, err_code: i8) {
+        let tx = self.build();
+        let err = self
+            .context
+            .verify_tx(&tx, MAX_CYCLES)
+            .expect_err("verification should fail");
+        assert_script_error(err, err_code);
+    }
+}
+
+/// Sign the input group of `tx` as secp256k1-blake160-sighash-all, the group is the single
+/// input at `group_index`, and place the signature in the lock of its witness
+fn sign_group(tx: TransactionView, privkey: &Privkey, group_index: usize) -> TransactionView {
+    let zeroed_witness = WitnessArgs::new_builder()
+        .lock(Some(Bytes::from(vec![0u8; 65])).pack())
+        .build();
+    let mut hasher = new_blake2b();
+    hasher.update(&tx.hash().raw_data());
+    hasher.update(&(zeroed_witness.as_slice().len() as u64).to_le_bytes());
+    hasher.update(zeroed_witness.as_slice());
+    let mut message = [0u8; 32];
+    hasher.finalize(&mut message);
+
+    let signature = privkey
+        .sign_recoverable(&message.into())
+        .expect("sign")
+        .serialize();
+    let witness = zeroed_witness
+        .as_builder()
+        .lock(Some(Bytes::from(signature)).pack())
+        .build();
+    let mut witnesses: Vec<Bytes> = tx.witnesses().into_iter().map(|w| w.unpack()).collect();
+    witnesses[group_index] = witness.as_bytes();
+    tx.as_advanced_builder()
+        .set_witnesses(witnesses.iter().map(|witness| witness.pack()).collect())
+        .build()
+}
+
+/// blake160 of the compressed pubkey, as the secp256k1 blake160 lock args
+fn pubkey_hash(privkey: &Privkey) -> [u8; 20] {
+    let pubkey = privkey.pubkey().expect("pubkey").serialize();
+    blake2b_256(pubkey)[..20].try_into().unwrap()
+}
+
+/// secp256k1 blake160 sighash all lock of `privkey`, not deployed
+fn secp256k1_lock(privkey: &Privkey) -> Script {
+    let code = BUNDLED_CELL
+        .get("specs/cells/secp256k1_blake160_sighash_all")
+        .expect("secp256k1 lock");
+    Script::new_builder()
+        .code_hash(blake2b_256(code).pack())
+        .hash_type(ScriptHashType::Data1.into())
+        .args(Bytes::copy_from_slice(&pubkey_hash(privkey)).pack())
+        .build()
+}
+
+/// Signature of the shadow cells in their witness lock
+enum ShadowSignature {
+    /// by the expected key
+    Key,
+    OtherKey,
+    Missing,
+}
+
 // generated unit test for contract shadow-lock
 #[test]
 fn test_shadow_lock() {
@@ -597,3 +976,1404 @@ fn test_shadow_lock_forbid_trade_pass_verify() {
         .expect("pass verification");
     println!("consume cycles: {}", cycles);
 }
+
+/// Ticket cell carrying `ticket_data` unlocks the shadow cell delegated to the ticket data hash
+fn delegate_cell_data_test(ticket_data: &[u8]) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+
+    // delegate cell data = true, content-addressed ticket, the key is the hash of its data
+    let shadow =
+        test.shadow_lock(LockArgs::new(0b00010000, blake2b_256(b"shadow lock ticket")).build());
+
+    test.input(cell(1000, &holder), ticket_data);
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(1000, &holder), ticket_data);
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_delegate_cell_data() {
+    delegate_cell_data_test(b"shadow lock ticket").pass();
+}
+
+#[test]
+fn test_shadow_lock_delegate_cell_data_failure() {
+    // carries a different ticket so it can not unlock
+    delegate_cell_data_test(b"forged ticket").fail(-110);
+}
+
+enum KeyNft {
+    Presented,
+    Missing,
+}
+
+/// Key NFT type delegate with forbid trade, the shadow cell is moved to the `receiver` lock
+fn forbid_trade_type_delegate_test(key_nft: KeyNft, receiver: u8) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let key_type = test.script(3);
+    let asset_type = test.script(6);
+    let receiver = test.script(receiver);
+
+    // delegate script hash = type.hash
+    // forbid trade = true
+    let shadow = test.shadow_lock(LockArgs::new(0b00000011, hash(&key_type)).build());
+
+    if let KeyNft::Presented = key_nft {
+        test.input(typed_cell(1000, &holder, &key_type), &[]);
+        test.output(typed_cell(1000, &holder, &key_type), &[]);
+    }
+    test.input(typed_cell(500, &shadow, &asset_type), &[]);
+    test.output(typed_cell(500, &receiver, &asset_type), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_forbid_trade_type_delegate_pass_verify() {
+    // shadow cell goes back to the lock of the key NFT holder
+    forbid_trade_type_delegate_test(KeyNft::Presented, 1).pass();
+}
+
+#[test]
+fn test_shadow_lock_forbid_trade_type_delegate_verify() {
+    // shadow cell is sent to a lock unrelated to the key NFT holder
+    forbid_trade_type_delegate_test(KeyNft::Presented, 2).fail(-111);
+}
+
+#[test]
+fn test_shadow_lock_forbid_trade_type_delegate_missing() {
+    // key NFT is not presented in inputs
+    forbid_trade_type_delegate_test(KeyNft::Missing, 1).fail(-110);
+}
+
+/// 2 of 3 committee NFTs are required, the first `presented_nfts` are in inputs
+fn committee_delegate_test(presented_nfts: usize) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let committee = (10..13u8).map(|seed| test.script(seed)).collect::<Vec<_>>();
+
+    let entries = committee
+        .iter()
+        .map(|nft| hash_entry(1, hash(nft)))
+        .collect::<Vec<_>>();
+    let shadow = test.shadow_lock(LockArgs::list(0, 2, &entries).build());
+
+    for nft in committee.iter().take(presented_nfts) {
+        test.input(typed_cell(1000, &holder, nft), &[]);
+    }
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_delegate_list_threshold() {
+    committee_delegate_test(2).pass();
+}
+
+#[test]
+fn test_shadow_lock_delegate_list_threshold_not_met() {
+    committee_delegate_test(1).fail(-110);
+}
+
+enum UserCells {
+    /// another cell of the user is presented besides the NFT
+    NftAndSpare,
+    NftOnly,
+}
+
+/// 2-of-2 list of the user lock and the NFT type, the NFT is held by the user
+fn one_cell_per_delegate_test(user_cells: UserCells) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let user = test.script(1);
+    let nft = test.script(10);
+
+    let entries = [hash_entry(0, hash(&user)), hash_entry(1, hash(&nft))];
+    let shadow = test.shadow_lock(LockArgs::list(0, 2, &entries).build());
+
+    test.input(typed_cell(1000, &user, &nft), &[]);
+    if let UserCells::NftAndSpare = user_cells {
+        test.input(cell(1000, &user), &[]);
+    }
+    test.input(cell(500, &shadow), &[]);
+    test.output(typed_cell(1000, &user, &nft), &[]);
+    test.output(cell(500, &user), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_delegate_list_one_cell_per_delegate() {
+    one_cell_per_delegate_test(UserCells::NftAndSpare).pass();
+}
+
+#[test]
+fn test_shadow_lock_delegate_list_cell_counted_once() {
+    one_cell_per_delegate_test(UserCells::NftOnly).fail(-110);
+}
+
+enum ExpiryReceiver {
+    ShadowLock,
+    FallbackOwner,
+}
+
+/// Delegated to lock 1 until block 100, then it goes back to the fallback owner lock 4.
+/// `keys` are the locks presented in inputs, the shadow cell is spent with `since`
+/// and moved to `receiver`, with a header dep at `header_number` if any.
+fn expiry_test(
+    keys: &[u8],
+    since: u64,
+    header_number: Option<u64>,
+    receiver: ExpiryReceiver,
+) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let delegate = test.script(1);
+    let owner = test.script(4);
+
+    let mut expiry = 100u64.to_le_bytes().to_vec();
+    expiry.extend(hash(&owner));
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&delegate))
+            .extension(0x01, &expiry)
+            .build(),
+    );
+
+    for seed in keys {
+        let key = test.script(*seed);
+        test.input(cell(1000, &key), &[]);
+    }
+    test.input_with_since(cell(500, &shadow), &[], since);
+    let receiver = match receiver {
+        ExpiryReceiver::ShadowLock => shadow,
+        ExpiryReceiver::FallbackOwner => owner,
+    };
+    test.output(cell(500, &receiver), &[]);
+    if let Some(number) = header_number {
+        let header = test.header(number, 0);
+        test.header_dep(&header);
+    }
+    test
+}
+
+#[test]
+fn test_shadow_lock_expiry_before_expire() {
+    expiry_test(&[1], 0, Some(99), ExpiryReceiver::ShadowLock).pass();
+}
+
+#[test]
+fn test_shadow_lock_expiry_delegate_moves_cell_out() {
+    // the delegate could keep the cell after the expiry
+    expiry_test(&[1], 0, Some(99), ExpiryReceiver::FallbackOwner).fail(-114);
+}
+
+#[test]
+fn test_shadow_lock_expiry_delegate_rejected() {
+    // absolute block number since proves the delegation has expired
+    expiry_test(&[1], 100, None, ExpiryReceiver::FallbackOwner).fail(-113);
+}
+
+#[test]
+fn test_shadow_lock_expiry_delegate_rejected_by_header_dep() {
+    // header dep proves the delegation has expired
+    expiry_test(&[1], 0, Some(150), ExpiryReceiver::FallbackOwner).fail(-113);
+}
+
+#[test]
+fn test_shadow_lock_expiry_fallback_owner() {
+    expiry_test(&[4], 120, None, ExpiryReceiver::FallbackOwner).pass();
+}
+
+enum OwnerCell {
+    Presented,
+    Missing,
+}
+
+/// Delegated to a burned key NFT type with the owner lock 4 as recovery, the shadow cell
+/// is moved to the `receiver` lock, or kept under the shadow lock if None
+fn owner_recovery_test(mode: u8, owner_cell: OwnerCell, receiver: Option<u8>) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let owner = test.script(4);
+    let asset_type = test.script(6);
+
+    // delegate script hash = type.hash, the key NFT has been burned
+    let args = LockArgs::new(mode | 0b00000001, [3u8; 32]).extension(0x02, &hash(&owner));
+    let shadow = test.shadow_lock(args.build());
+
+    if let OwnerCell::Presented = owner_cell {
+        test.key(&owner);
+    }
+    test.input(typed_cell(500, &shadow, &asset_type), &[]);
+    let receiver = match receiver {
+        Some(seed) => test.script(seed),
+        None => shadow,
+    };
+    test.output(typed_cell(500, &receiver, &asset_type), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_owner_recovery() {
+    // forbid trade = true
+    owner_recovery_test(0b00000010, OwnerCell::Presented, Some(4)).pass();
+}
+
+#[test]
+fn test_shadow_lock_owner_recovery_missing_owner() {
+    owner_recovery_test(0b00000000, OwnerCell::Missing, Some(4)).fail(-115);
+}
+
+#[test]
+fn test_shadow_lock_owner_recovery_forbid_trade_verify() {
+    // forbid trade = true
+    owner_recovery_test(0b00000010, OwnerCell::Presented, Some(2)).fail(-111);
+}
+
+#[test]
+fn test_shadow_lock_owner_recovery_self_destruction_verify() {
+    // self destruction = true
+    owner_recovery_test(0b00000100, OwnerCell::Presented, None).fail(-112);
+}
+
+enum CellDepProof {
+    Enabled,
+    Disabled,
+}
+
+enum HolderCell {
+    /// plain cell of the key NFT holder, proves the holder signs the transaction
+    Presented,
+    Missing,
+}
+
+/// Key NFT held by lock 1 is only referenced in cell deps, the delegate is the lock
+/// if `key_kind` is 0, or the key NFT type if 1
+fn cell_dep_proof_test(
+    key_kind: u8,
+    proof: CellDepProof,
+    holder_cell: HolderCell,
+) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let key_type = test.script(3);
+
+    let ref_hash = match key_kind {
+        0 => hash(&holder),
+        _ => hash(&key_type),
+    };
+    let mut args = LockArgs::new(key_kind, ref_hash);
+    if let CellDepProof::Enabled = proof {
+        args = args.extension(0x03, &[]);
+    }
+    let shadow = test.shadow_lock(args.build());
+
+    test.cell_dep(typed_cell(1000, &holder, &key_type), &[]);
+    if let HolderCell::Presented = holder_cell {
+        test.input(cell(1000, &holder), &[]);
+    }
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_cell_dep_proof() {
+    cell_dep_proof_test(1, CellDepProof::Enabled, HolderCell::Presented).pass();
+}
+
+#[test]
+fn test_shadow_lock_cell_dep_proof_disabled() {
+    cell_dep_proof_test(1, CellDepProof::Disabled, HolderCell::Presented).fail(-110);
+}
+
+#[test]
+fn test_shadow_lock_cell_dep_proof_lock_delegate() {
+    // lock delegates can not be proven by cell deps
+    cell_dep_proof_test(0, CellDepProof::Enabled, HolderCell::Missing).fail(-110);
+}
+
+#[test]
+fn test_shadow_lock_cell_dep_proof_without_holder() {
+    // anyone can reference the key NFT in cell deps, the holder lock must be presented
+    cell_dep_proof_test(1, CellDepProof::Enabled, HolderCell::Missing).fail(-110);
+}
+
+/// Any NFT of cluster [5u8; 32] can unlock, the presented NFT is of `nft_cluster_id`
+fn collection_delegate_test(nft_cluster_id: [u8; 32]) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    // NFT type args = <cluster id, 32 bytes><token id, 8 bytes>
+    let nft = test.script_with_args(&[&nft_cluster_id[..], &[7u8; 8]].concat());
+
+    let entry = pattern_entry(3, &nft, Some(&[5u8; 32]));
+    let shadow = test.shadow_lock(LockArgs::list(0, 1, &[entry]).build());
+
+    test.input(typed_cell(1000, &holder, &nft), &[]);
+    test.input(cell(500, &shadow), &[]);
+    test.output(typed_cell(1000, &holder, &nft), &[]);
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_collection_delegate() {
+    collection_delegate_test([5u8; 32]).pass();
+}
+
+#[test]
+fn test_shadow_lock_collection_delegate_other_cluster() {
+    collection_delegate_test([6u8; 32]).fail(-110);
+}
+
+#[test]
+fn test_shadow_lock_collection_delegate_invalid_hash_type() {
+    let mut test = ShadowLockTest::new();
+    let nft = test.script(10);
+    let mut entry = pattern_entry(3, &nft, Some(&[]));
+    entry[33] = 3;
+    let shadow = test.shadow_lock(LockArgs::list(0, 1, &[entry]).build());
+
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &nft), &[]);
+    test.fail(-4);
+}
+
+/// Any lock of the family can unlock, the family defaults to the code hash of the member lock
+fn lock_family_delegate_test(family_code_hash: Option<[u8; 32]>) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    // any pool member lock of the family
+    let member = test.script_with_args(&[9u8; 20]);
+
+    let mut entry = pattern_entry(4, &member, None);
+    if let Some(code_hash) = family_code_hash {
+        entry[1..33].copy_from_slice(&code_hash);
+    }
+    let shadow = test.shadow_lock(LockArgs::list(0, 1, &[entry]).build());
+
+    test.input(cell(1000, &member), &[]);
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(1500, &member), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_lock_family_delegate() {
+    lock_family_delegate_test(None).pass();
+}
+
+#[test]
+fn test_shadow_lock_lock_family_delegate_other_family() {
+    lock_family_delegate_test(Some([0xaau8; 32])).fail(-110);
+}
+
+enum ExecDelegateKey {
+    /// signs the shadow cell
+    Signed,
+    /// signature of another key
+    WrongKey,
+    Unsigned,
+    /// signs, but listed by the registry
+    Revoked,
+    /// a cell of the delegate lock is unlocked instead, through the secp256k1 lock itself
+    Cell,
+}
+
+/// Exec delegate value of `privkey`: its ckb-auth value, then the code of its delegate lock
+fn exec_delegate_value(test: &mut ShadowLockTest, privkey: &Privkey, delegate: &Script) -> Vec<u8> {
+    [test.auth(privkey), script_value(delegate)[..33].to_vec()].concat()
+}
+
+/// The signature of the secp256k1 lock delegate is verified by ckb-auth, no cell locked by it
+/// is needed. The registry of type 9 lists the delegate lock hash once `Revoked`.
+fn exec_delegate_test(key: ExecDelegateKey) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let privkey = Generator::random_privkey();
+    let delegate = secp256k1_lock(&privkey);
+
+    let exec_delegate = exec_delegate_value(&mut test, &privkey, &delegate);
+    let mut args = LockArgs::new(0, hash(&delegate)).extension(0x04, &exec_delegate);
+    if let ExecDelegateKey::Revoked = key {
+        let registry_type = type_id_script(9);
+        args = args.extension(0x0f, &hash(&registry_type));
+        test.cell_dep(typed_cell(1000, &holder, &registry_type), &hash(&delegate));
+    }
+    let shadow = test.shadow_lock(args.build());
+
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &holder), &[]);
+    let signature = match key {
+        ExecDelegateKey::Signed | ExecDelegateKey::Revoked => ShadowSignature::Key,
+        ExecDelegateKey::WrongKey => ShadowSignature::OtherKey,
+        ExecDelegateKey::Unsigned => ShadowSignature::Missing,
+        ExecDelegateKey::Cell => {
+            test.secp256k1_deps();
+            test.key(&delegate);
+            test.sign(1, &privkey);
+            ShadowSignature::Missing
+        }
+    };
+    test.sign_shadow(0, &privkey, signature);
+    test
+}
+
+#[test]
+fn test_shadow_lock_exec_delegate() {
+    exec_delegate_test(ExecDelegateKey::Signed).pass();
+}
+
+#[test]
+fn test_shadow_lock_exec_delegate_wrong_key() {
+    // ckb-auth rejects the signature with its mismatched error
+    exec_delegate_test(ExecDelegateKey::WrongKey).fail(101);
+}
+
+#[test]
+fn test_shadow_lock_exec_delegate_unsigned() {
+    exec_delegate_test(ExecDelegateKey::Unsigned).fail(-110);
+}
+
+#[test]
+fn test_shadow_lock_exec_delegate_revoked() {
+    exec_delegate_test(ExecDelegateKey::Revoked).fail(-110);
+}
+
+#[test]
+fn test_shadow_lock_exec_delegate_cell() {
+    exec_delegate_test(ExecDelegateKey::Cell).pass();
+}
+
+/// 2-of-2 list of the one-shot key NFT type 2 and the exec delegate, the key goes to `key_output`
+fn exec_delegate_burn_test(key_output: KeyOutput) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let key_type = test.script(2);
+    let privkey = Generator::random_privkey();
+    let delegate = secp256k1_lock(&privkey);
+
+    let entries = [
+        hash_entry(1, hash(&key_type)),
+        hash_entry(0, hash(&delegate)),
+    ];
+    let exec_delegate = exec_delegate_value(&mut test, &privkey, &delegate);
+    let args = LockArgs::list(0, 2, &entries)
+        .extension(0x04, &exec_delegate)
+        .extension(0x0a, &[]);
+    let shadow = test.shadow_lock(args.build());
+
+    test.input(typed_cell(1000, &holder, &key_type), &[]);
+    test.input(cell(500, &shadow), &[]);
+    match key_output {
+        KeyOutput::Burned => test.output(cell(1000, &holder), &[]),
+        KeyOutput::Unchanged => test.output(typed_cell(1000, &holder, &key_type), &[]),
+        KeyOutput::Modified => test.output(typed_cell(1000, &holder, &key_type), b"used key"),
+    }
+    test.output(cell(500, &holder), &[]);
+    test.sign(1, &privkey);
+    test
+}
+
+#[test]
+fn test_shadow_lock_exec_delegate_key_burned() {
+    exec_delegate_burn_test(KeyOutput::Burned).pass();
+}
+
+#[test]
+fn test_shadow_lock_exec_delegate_key_not_burned() {
+    // the burn check runs before the signature is verified
+    exec_delegate_burn_test(KeyOutput::Unchanged).fail(-122);
+}
+
+/// Args are <version header><mode><delegate script hash of lock 1><tail>
+fn versioned_args_test(header: &[u8], mode: u8, tail: &[u8]) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+
+    let args = [header, &build_lock_args(mode, hash(&holder)), tail].concat();
+    let shadow = test.shadow_lock(Bytes::from(args));
+
+    test.input(cell(1000, &holder), &[]);
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(1500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_args_version_1() {
+    versioned_args_test(&[0x81], 0b00000000, &[]).pass();
+}
+
+#[test]
+fn test_shadow_lock_args_unsupported_version() {
+    versioned_args_test(&[0x82], 0b00000000, &[]).fail(-116);
+}
+
+#[test]
+fn test_shadow_lock_args_reserved_flag_bits() {
+    versioned_args_test(&[0x81], 0b10000000, &[]).fail(-116);
+}
+
+#[test]
+fn test_shadow_lock_args_version_1_flag_without_header() {
+    versioned_args_test(&[], 0b00010000, &[]).fail(-116);
+}
+
+#[test]
+fn test_shadow_lock_args_unknown_extension() {
+    versioned_args_test(&[0x81], 0b01000000, &[0x7f, 4, 1, 1, 1, 1]).fail(-116);
+}
+
+#[test]
+fn test_shadow_lock_args_trailing_bytes() {
+    versioned_args_test(&[0x81], 0b00000000, &[0]).fail(-4);
+}
+
+// remaining uses counter as the first 8 bytes of shadow cell data, followed by the cell's own data
+fn usage_data(remaining_uses: u64) -> Vec<u8> {
+    [&remaining_uses.to_le_bytes()[..], b"subscription"].concat()
+}
+
+enum UsageLimitOutput {
+    /// re-created with the remaining uses and the capacity
+    Successor(u64, u64),
+    /// released to the delegate
+    Released,
+}
+
+/// The shadow cell of 500 with `remaining_uses` goes to `output`
+fn usage_limit_test(remaining_uses: u64, output: UsageLimitOutput) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let shadow = test.shadow_lock(LockArgs::new(0, hash(&holder)).extension(0x05, &[]).build());
+
+    test.key(&holder);
+    test.input(cell(500, &shadow), &usage_data(remaining_uses));
+    match output {
+        UsageLimitOutput::Successor(uses, capacity) => {
+            test.output(cell(capacity, &shadow), &usage_data(uses))
+        }
+        UsageLimitOutput::Released => test.output(cell(500, &holder), &[]),
+    }
+    test
+}
+
+#[test]
+fn test_shadow_lock_usage_limit() {
+    usage_limit_test(3, UsageLimitOutput::Successor(2, 500)).pass();
+}
+
+#[test]
+fn test_shadow_lock_usage_limit_not_decremented() {
+    usage_limit_test(3, UsageLimitOutput::Successor(3, 500)).fail(-117);
+}
+
+#[test]
+fn test_shadow_lock_usage_limit_capacity_drained() {
+    usage_limit_test(3, UsageLimitOutput::Successor(2, 100)).fail(-117);
+}
+
+#[test]
+fn test_shadow_lock_usage_limit_release_early() {
+    usage_limit_test(1, UsageLimitOutput::Released).fail(-117);
+}
+
+#[test]
+fn test_shadow_lock_usage_limit_release() {
+    usage_limit_test(0, UsageLimitOutput::Released).pass();
+}
+
+// relative since flag with block number metric
+const RELATIVE_BLOCK_SINCE: u64 = 0x8000_0000_0000_0000;
+
+/// At most one unlock every 100 blocks, `shadow_cells` cells of 500 are spent with `input_since`
+/// and re-created by outputs of the `successors` capacities, or moved to the holder if none
+fn rate_limit_test(input_since: u64, shadow_cells: usize, successors: &[u64]) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&holder))
+            .extension(0x06, &(RELATIVE_BLOCK_SINCE | 100).to_le_bytes())
+            .build(),
+    );
+
+    test.key(&holder);
+    for _ in 0..shadow_cells {
+        test.input_with_since(cell(500, &shadow), &[], input_since);
+    }
+    for capacity in successors {
+        test.output(cell(*capacity, &shadow), &[]);
+    }
+    if successors.is_empty() {
+        test.output(cell(500 * shadow_cells as u64, &holder), &[]);
+    }
+    test
+}
+
+#[test]
+fn test_shadow_lock_rate_limit() {
+    rate_limit_test(RELATIVE_BLOCK_SINCE | 100, 1, &[500]).pass();
+}
+
+#[test]
+fn test_shadow_lock_rate_limit_too_early() {
+    rate_limit_test(RELATIVE_BLOCK_SINCE | 99, 1, &[500]).fail(-118);
+}
+
+#[test]
+fn test_shadow_lock_rate_limit_absolute_since() {
+    rate_limit_test(100, 1, &[500]).fail(-118);
+}
+
+#[test]
+fn test_shadow_lock_rate_limit_not_recreated() {
+    rate_limit_test(RELATIVE_BLOCK_SINCE | 100, 1, &[]).fail(-118);
+}
+
+#[test]
+fn test_shadow_lock_rate_limit_capacity_drained() {
+    rate_limit_test(RELATIVE_BLOCK_SINCE | 100, 1, &[100]).fail(-118);
+}
+
+#[test]
+fn test_shadow_lock_rate_limit_each_cell_recreated() {
+    rate_limit_test(RELATIVE_BLOCK_SINCE | 100, 2, &[500, 500]).pass();
+}
+
+#[test]
+fn test_shadow_lock_rate_limit_shared_successor() {
+    rate_limit_test(RELATIVE_BLOCK_SINCE | 100, 2, &[500]).fail(-118);
+}
+
+fn merkle_parent(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    let mut hasher = new_blake2b();
+    hasher.update(&left);
+    hasher.update(&right);
+    let mut ret = [0u8; 32];
+    hasher.finalize(&mut ret);
+    ret
+}
+
+enum DestinationProof {
+    /// inclusion proof of lock 11 in the witness
+    Lock11,
+    Missing,
+}
+
+/// Custodial locks 10 to 13 are allowlisted, the shadow cell is moved to the `destination` lock
+fn destination_allowlist_test(destination: u8, proof: DestinationProof) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let custodial = (10..14u8)
+        .map(|seed| hash(&test.script(seed)))
+        .collect::<Vec<_>>();
+    let root = merkle_parent(
+        merkle_parent(custodial[0], custodial[1]),
+        merkle_parent(custodial[2], custodial[3]),
+    );
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&holder))
+            .extension(0x07, &root)
+            .build(),
+    );
+    let destination = test.script(destination);
+
+    test.key(&holder);
+    test.input(cell(500, &shadow), b"composed asset");
+    test.output(cell(500, &destination), b"composed asset");
+    if let DestinationProof::Lock11 = proof {
+        // <sibling count, 1 byte><siblings> in the lock field of the shadow cell witness
+        let siblings = [custodial[0], merkle_parent(custodial[2], custodial[3])];
+        let lock = [&[2u8][..], &siblings.concat()].concat();
+        let witness = WitnessArgs::new_builder()
+            .lock(Some(Bytes::from(lock)).pack())
+            .build();
+        test.witness(1, witness.as_bytes());
+    }
+    test
+}
+
+#[test]
+fn test_shadow_lock_destination_allowlist() {
+    destination_allowlist_test(11, DestinationProof::Lock11).pass();
+}
+
+#[test]
+fn test_shadow_lock_destination_not_allowlisted() {
+    destination_allowlist_test(2, DestinationProof::Lock11).fail(-119);
+}
+
+#[test]
+fn test_shadow_lock_destination_proof_missing() {
+    destination_allowlist_test(11, DestinationProof::Missing).fail(-119);
+}
+
+enum RoyaltyAsset {
+    Capacity,
+    /// UDT of type 8
+    Udt,
+}
+
+/// Royalty of 300 to lock 7 in `asset`, the shadow cell is input 1
+/// and the beneficiary is paid `paid` by output `payment_index`
+fn royalty_test(asset: RoyaltyAsset, paid: u128, payment_index: usize) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let beneficiary = test.script(7);
+    // stands in for an xUDT type script
+    let udt_type = test.script(8);
+
+    let mut royalty = hash(&beneficiary).to_vec();
+    royalty.extend(300u128.to_le_bytes());
+    if let RoyaltyAsset::Udt = asset {
+        royalty.extend(hash(&udt_type));
+    }
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&holder))
+            .extension(0x08, &royalty)
+            .build(),
+    );
+
+    test.input(cell(2000, &holder), &[]);
+    test.input(cell(500, &shadow), &[]);
+    for index in 0..3 {
+        if index != payment_index {
+            test.output(cell(500, &holder), &[]);
+            continue;
+        }
+        match asset {
+            RoyaltyAsset::Capacity => test.output(cell(paid as u64, &beneficiary), &[]),
+            RoyaltyAsset::Udt => test.output(
+                typed_cell(1000, &beneficiary, &udt_type),
+                &paid.to_le_bytes(),
+            ),
+        }
+    }
+    test
+}
+
+#[test]
+fn test_shadow_lock_royalty_capacity() {
+    royalty_test(RoyaltyAsset::Capacity, 300, 1).pass();
+}
+
+#[test]
+fn test_shadow_lock_royalty_capacity_not_enough() {
+    royalty_test(RoyaltyAsset::Capacity, 299, 1).fail(-120);
+}
+
+#[test]
+fn test_shadow_lock_royalty_udt() {
+    royalty_test(RoyaltyAsset::Udt, 300, 1).pass();
+}
+
+#[test]
+fn test_shadow_lock_royalty_udt_not_enough() {
+    // the beneficiary output holds plenty of capacity, but not enough UDT
+    royalty_test(RoyaltyAsset::Udt, 299, 1).fail(-120);
+}
+
+#[test]
+fn test_shadow_lock_royalty_paid_at_other_index() {
+    // the payment is not paired with the shadow cell, it may pay for another one
+    royalty_test(RoyaltyAsset::Capacity, 300, 2).fail(-120);
+}
+
+enum KeyOutput {
+    Unchanged,
+    Modified,
+    Burned,
+}
+
+/// Key NFT type delegate with the `extension` flag, the key NFT goes to `key_output`
+fn key_delegate_test(extension: u8, key_output: KeyOutput) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let key_type = test.script(2);
+    let shadow = test.shadow_lock(
+        LockArgs::new(0b00000001, hash(&key_type))
+            .extension(extension, &[])
+            .build(),
+    );
+
+    test.input(typed_cell(1000, &holder, &key_type), b"key");
+    test.input(cell(500, &shadow), &[]);
+    match key_output {
+        KeyOutput::Unchanged => test.output(typed_cell(1000, &holder, &key_type), b"key"),
+        KeyOutput::Modified => test.output(typed_cell(1000, &holder, &key_type), b"used key"),
+        KeyOutput::Burned => test.output(cell(1000, &holder), &[]),
+    }
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_preserve_delegate() {
+    key_delegate_test(0x09, KeyOutput::Unchanged).pass();
+}
+
+#[test]
+fn test_shadow_lock_preserve_delegate_modified() {
+    key_delegate_test(0x09, KeyOutput::Modified).fail(-121);
+}
+
+#[test]
+fn test_shadow_lock_preserve_delegate_burned() {
+    key_delegate_test(0x09, KeyOutput::Burned).fail(-121);
+}
+
+#[test]
+fn test_shadow_lock_burn_delegate() {
+    key_delegate_test(0x0a, KeyOutput::Burned).pass();
+}
+
+#[test]
+fn test_shadow_lock_burn_delegate_kept() {
+    // modified data does not burn the key, it still carries the type
+    key_delegate_test(0x0a, KeyOutput::Modified).fail(-122);
+}
+
+/// Key NFT type delegate whose data[4..12] must be "identity", the key NFT carries `key_data`
+fn delegate_data_slice_test(key_data: &[u8]) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let key_type = test.script(2);
+    let shadow = test.shadow_lock(
+        LockArgs::new(0b00000001, hash(&key_type))
+            .data_hash(blake2b_256(b"identity"))
+            .extension(0x0b, &[4u32.to_le_bytes(), 8u32.to_le_bytes()].concat())
+            .build(),
+    );
+
+    test.input(typed_cell(1000, &holder, &key_type), key_data);
+    test.input(cell(500, &shadow), &[]);
+    test.output(typed_cell(1000, &holder, &key_type), key_data);
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_delegate_data_slice() {
+    delegate_data_slice_test(b"v1: identity, level 1").pass();
+}
+
+#[test]
+fn test_shadow_lock_delegate_data_slice_updated_metadata() {
+    // data outside of the slice is updated
+    delegate_data_slice_test(b"v2: identity, level 99").pass();
+}
+
+#[test]
+fn test_shadow_lock_delegate_data_slice_mismatch() {
+    delegate_data_slice_test(b"v1: impostor, level 1").fail(-110);
+}
+
+#[test]
+fn test_shadow_lock_delegate_data_slice_too_short() {
+    delegate_data_slice_test(b"v1: ident").fail(-110);
+}
+
+/// At least 1000 tokens of the UDT type 8 must be presented, spread over `balances` cells
+fn token_balance_delegate_test(balances: &[u128]) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    // stands in for an xUDT type script
+    let udt_type = test.script(8);
+
+    let mut entry = vec![5u8];
+    entry.extend(hash(&udt_type));
+    entry.extend(1000u128.to_le_bytes());
+    let shadow = test.shadow_lock(LockArgs::list(0, 1, &[entry]).build());
+
+    for balance in balances {
+        test.input(typed_cell(1000, &holder, &udt_type), &balance.to_le_bytes());
+        test.output(typed_cell(1000, &holder, &udt_type), &balance.to_le_bytes());
+    }
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_token_balance_delegate() {
+    token_balance_delegate_test(&[1000]).pass();
+}
+
+#[test]
+fn test_shadow_lock_token_balance_delegate_combined() {
+    token_balance_delegate_test(&[600, 500]).pass();
+}
+
+#[test]
+fn test_shadow_lock_token_balance_delegate_not_enough() {
+    token_balance_delegate_test(&[999]).fail(-110);
+}
+
+// absolute since flag with timestamp metric
+const ABSOLUTE_TIMESTAMP_SINCE: u64 = 0x4000_0000_0000_0000;
+
+enum InputHeader {
+    /// in `header_deps`, proving when the inputs were created
+    Dep,
+    Missing,
+}
+
+/// `window` is a list of (extension kind, absolute since),
+/// `input_header` is the (number, timestamp in milliseconds) of the block the inputs are in
+fn time_window_test(
+    window: &[(u8, u64)],
+    input_header: (u64, u64),
+    header_dep: InputHeader,
+) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let args = window
+        .iter()
+        .fold(LockArgs::new(0, hash(&holder)), |args, (kind, since)| {
+            args.extension(*kind, &since.to_le_bytes())
+        });
+    let shadow = test.shadow_lock(args.build());
+
+    let key_out_point = test.key(&holder);
+    let shadow_out_point = test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &holder), &[]);
+
+    let (number, timestamp) = input_header;
+    let header = test.header(number, timestamp);
+    test.context
+        .link_cell_with_block(key_out_point, header.hash(), 0);
+    test.context
+        .link_cell_with_block(shadow_out_point, header.hash(), 0);
+    if let InputHeader::Dep = header_dep {
+        test.header_dep(&header);
+    }
+    test
+}
+
+#[test]
+fn test_shadow_lock_time_window() {
+    time_window_test(&[(0x0c, 100)], (150, 0), InputHeader::Dep).pass();
+}
+
+#[test]
+fn test_shadow_lock_time_window_not_started() {
+    time_window_test(&[(0x0c, 100)], (50, 0), InputHeader::Dep).fail(-123);
+}
+
+#[test]
+fn test_shadow_lock_time_window_not_after_rejected() {
+    // the end of the window can not be enforced
+    time_window_test(&[(0x0c, 100), (0x0d, 200)], (150, 0), InputHeader::Dep).fail(-116);
+}
+
+#[test]
+fn test_shadow_lock_time_window_without_header() {
+    time_window_test(&[(0x0c, 100)], (150, 0), InputHeader::Missing).fail(-123);
+}
+
+#[test]
+fn test_shadow_lock_time_window_timestamp() {
+    // not before 1000 seconds, the header is at 1500 seconds
+    time_window_test(
+        &[(0x0c, ABSOLUTE_TIMESTAMP_SINCE | 1000)],
+        (10, 1_500_000),
+        InputHeader::Dep,
+    )
+    .pass();
+}
+
+/// Delegate unlocks must be co-signed by the co-signer key, verified by ckb-auth
+fn co_signature_test(signature: ShadowSignature) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let co_signer = Generator::random_privkey();
+
+    let co_signer_value = test.auth(&co_signer);
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&holder))
+            .extension(0x0e, &co_signer_value)
+            .build(),
+    );
+
+    test.key(&holder);
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &holder), &[]);
+    test.sign_shadow(1, &co_signer, signature);
+    test
+}
+
+#[test]
+fn test_shadow_lock_co_signature() {
+    co_signature_test(ShadowSignature::Key).pass();
+}
+
+#[test]
+fn test_shadow_lock_co_signature_wrong_key() {
+    // ckb-auth rejects the signature with its mismatched error
+    co_signature_test(ShadowSignature::OtherKey).fail(101);
+}
+
+#[test]
+fn test_shadow_lock_co_signature_missing() {
+    co_signature_test(ShadowSignature::Missing).fail(-124);
+}
+
+#[test]
+fn test_shadow_lock_co_signature_with_exec_delegate() {
+    // only one of the signatures could be verified
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let privkey = Generator::random_privkey();
+    let delegate = secp256k1_lock(&privkey);
+    let exec_delegate = exec_delegate_value(&mut test, &privkey, &delegate);
+    let co_signer_value = test.auth(&privkey);
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&delegate))
+            .extension(0x04, &exec_delegate)
+            .extension(0x0e, &co_signer_value)
+            .build(),
+    );
+
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &holder), &[]);
+    test.sign(0, &privkey);
+    test.fail(-4);
+}
+
+enum RegistryCell {
+    /// Type ID cell listing revoked hashes in ascending order
+    Sorted,
+    /// same list, also revoking the key NFT
+    RevokingKey,
+    /// same list in descending order
+    Unsorted,
+    /// same list under a type anyone can create cells of
+    NotTypeId,
+    Missing,
+}
+
+/// Key NFT type delegate checked against the registry of type 9 in `registry` state
+fn revocation_registry_test(registry: RegistryCell) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let key_type = test.script(2);
+    let registry_type = match registry {
+        RegistryCell::NotTypeId => test.script(9),
+        _ => type_id_script(9),
+    };
+    let shadow = test.shadow_lock(
+        LockArgs::new(0b00000001, hash(&key_type))
+            .extension(0x0f, &hash(&registry_type))
+            .build(),
+    );
+
+    let mut revoked = vec![[0x11u8; 32], [0xeeu8; 32], [0x77u8; 32]];
+    if let RegistryCell::RevokingKey = registry {
+        revoked.push(hash(&key_type));
+    }
+    revoked.sort();
+    if let RegistryCell::Unsorted = registry {
+        revoked.reverse();
+    }
+    if !matches!(registry, RegistryCell::Missing) {
+        test.cell_dep(typed_cell(1000, &holder, &registry_type), &revoked.concat());
+    }
+
+    test.input(typed_cell(1000, &holder, &key_type), &[]);
+    test.input(cell(500, &shadow), &[]);
+    test.output(typed_cell(1000, &holder, &key_type), &[]);
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_revocation_registry() {
+    revocation_registry_test(RegistryCell::Sorted).pass();
+}
+
+#[test]
+fn test_shadow_lock_revocation_registry_revoked() {
+    revocation_registry_test(RegistryCell::RevokingKey).fail(-110);
+}
+
+#[test]
+fn test_shadow_lock_revocation_registry_missing() {
+    revocation_registry_test(RegistryCell::Missing).fail(-125);
+}
+
+#[test]
+fn test_shadow_lock_revocation_registry_unsorted() {
+    revocation_registry_test(RegistryCell::Unsorted).fail(-125);
+}
+
+#[test]
+fn test_shadow_lock_revocation_registry_not_type_id() {
+    // anyone could reference a copy of the registry with the key left out
+    revocation_registry_test(RegistryCell::NotTypeId).fail(-125);
+}
+
+enum ReceiverKey {
+    Valid,
+    Revoked,
+}
+
+/// Forbid trade with a 1-of-2 list of key NFT types 2 and 3, held by locks 1 and 4.
+/// The shadow cell goes to lock 4, whose key is in `receiver_key_state`.
+fn revoked_trade_target_test(receiver_key_state: ReceiverKey) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let registry_type = type_id_script(9);
+    let (holder, key) = (test.script(1), test.script(2));
+    let (receiver, receiver_key) = (test.script(4), test.script(3));
+    let entries = [
+        hash_entry(1, hash(&key)),
+        hash_entry(1, hash(&receiver_key)),
+    ];
+    let shadow = test.shadow_lock(
+        LockArgs::list(0b00000010, 1, &entries)
+            .extension(0x0f, &hash(&registry_type))
+            .build(),
+    );
+
+    let revoked = match receiver_key_state {
+        ReceiverKey::Valid => [0x11u8; 32],
+        ReceiverKey::Revoked => hash(&receiver_key),
+    };
+    test.cell_dep(typed_cell(1000, &holder, &registry_type), &revoked);
+    test.input(typed_cell(1000, &holder, &key), &[]);
+    test.input(typed_cell(1000, &receiver, &receiver_key), &[]);
+    test.input(cell(500, &shadow), &[]);
+    test.output(typed_cell(1000, &holder, &key), &[]);
+    test.output(typed_cell(1000, &receiver, &receiver_key), &[]);
+    test.output(cell(500, &receiver), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_revocation_registry_trade_target() {
+    revoked_trade_target_test(ReceiverKey::Valid).pass();
+}
+
+#[test]
+fn test_shadow_lock_revocation_registry_revoked_trade_target() {
+    revoked_trade_target_test(ReceiverKey::Revoked).fail(-111);
+}
+
+enum GuardianCell {
+    Unfrozen,
+    Frozen,
+    /// unfrozen, under a type anyone can create cells of
+    NotTypeId,
+    Missing,
+}
+
+/// Guarded by the guardian cell of type 9 in `guardian` state
+fn guardian_test(guardian: GuardianCell) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let guardian_type = match guardian {
+        GuardianCell::NotTypeId => test.script(9),
+        _ => type_id_script(9),
+    };
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&holder))
+            .extension(0x10, &hash(&guardian_type))
+            .build(),
+    );
+
+    // the first data byte is the frozen flag
+    match guardian {
+        GuardianCell::Unfrozen | GuardianCell::NotTypeId => {
+            test.cell_dep(typed_cell(1000, &holder, &guardian_type), &[0])
+        }
+        GuardianCell::Frozen => test.cell_dep(typed_cell(1000, &holder, &guardian_type), &[1]),
+        GuardianCell::Missing => {}
+    }
+    test.key(&holder);
+    test.input(cell(500, &shadow), &[]);
+    test.output(cell(500, &holder), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_guardian() {
+    guardian_test(GuardianCell::Unfrozen).pass();
+}
+
+#[test]
+fn test_shadow_lock_guardian_frozen() {
+    guardian_test(GuardianCell::Frozen).fail(-126);
+}
+
+#[test]
+fn test_shadow_lock_guardian_missing() {
+    guardian_test(GuardianCell::Missing).fail(-126);
+}
+
+#[test]
+fn test_shadow_lock_guardian_not_type_id() {
+    // anyone could create an unfrozen cell of the guardian type
+    guardian_test(GuardianCell::NotTypeId).fail(-126);
+}
+
+/// Listed for 300 shannons by the seller lock 7, the shadow cell is the first input, the buyer
+/// pays `paid` in the output at `payment_index`, and takes the shadow cell in the other one
+fn sale_test(paid: u64, payment_index: usize) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let holder = test.script(1);
+    let seller = test.script(7);
+    let buyer = test.script(3);
+
+    let mut sale = hash(&seller).to_vec();
+    sale.extend(300u128.to_le_bytes());
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&holder))
+            .extension(0x11, &sale)
+            .build(),
+    );
+
+    test.input(cell(500, &shadow), &[]);
+    // buyer's funds, the delegate is not presented
+    test.input(cell(1000, &buyer), &[]);
+    if payment_index == 0 {
+        test.output(cell(paid, &seller), &[]);
+        test.output(cell(500, &buyer), &[]);
+    } else {
+        test.output(cell(500, &buyer), &[]);
+        test.output(cell(paid, &seller), &[]);
+    }
+    test
+}
+
+#[test]
+fn test_shadow_lock_sale() {
+    sale_test(300, 0).pass();
+}
+
+#[test]
+fn test_shadow_lock_sale_underpaid() {
+    sale_test(299, 0).fail(-110);
+}
+
+#[test]
+fn test_shadow_lock_sale_payment_not_paired() {
+    // the payment is not at the index of the shadow cell input
+    sale_test(300, 1).fail(-110);
+}
+
+enum LeaseInput {
+    /// under the lease offer lock
+    Offer,
+    /// already under the return lease lock
+    Returned,
+}
+
+enum LeaseOutput {
+    ReturnLease,
+    /// under the return lease lock, with less capacity than the input
+    ReturnLeaseDrained,
+    SameLock,
+    Renter,
+}
+
+/// Leased to the renter lock 1 until block 1000, then it returns to the lessor lock 7
+fn lease_test(lease_input: LeaseInput, lease_output: LeaseOutput) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let renter = test.script(1);
+    let lessor = test.script(7);
+
+    let mut lease = 1000u64.to_le_bytes().to_vec();
+    lease.extend(hash(&lessor));
+    // lease offer args, and the return lease args with the lease as expiry
+    let offer_args = LockArgs::new(0, hash(&renter)).extension(0x12, &lease);
+    let return_args = offer_args.clone().extension(0x01, &lease);
+    let offer_lock = test.shadow_lock(offer_args.build());
+    let return_lock = test.shadow_lock(return_args.build());
+    let input_lock = match lease_input {
+        LeaseInput::Offer => offer_lock,
+        LeaseInput::Returned => return_lock.clone(),
+    };
+
+    test.key(&renter);
+    test.input(cell(500, &input_lock), b"leased asset");
+    let (receiver, capacity) = match lease_output {
+        LeaseOutput::ReturnLease => (return_lock, 500),
+        LeaseOutput::ReturnLeaseDrained => (return_lock, 100),
+        LeaseOutput::SameLock => (input_lock, 500),
+        LeaseOutput::Renter => (renter, 500),
+    };
+    test.output(cell(capacity, &receiver), b"leased asset");
+    test
+}
+
+#[test]
+fn test_shadow_lock_lease() {
+    lease_test(LeaseInput::Offer, LeaseOutput::ReturnLease).pass();
+}
+
+#[test]
+fn test_shadow_lock_lease_taken_by_renter() {
+    lease_test(LeaseInput::Offer, LeaseOutput::Renter).fail(-127);
+}
+
+#[test]
+fn test_shadow_lock_lease_capacity_drained() {
+    lease_test(LeaseInput::Offer, LeaseOutput::ReturnLeaseDrained).fail(-127);
+}
+
+#[test]
+fn test_shadow_lock_lease_without_return() {
+    // the offer lock has no lease end
+    lease_test(LeaseInput::Offer, LeaseOutput::SameLock).fail(-127);
+}
+
+#[test]
+fn test_shadow_lock_lease_returned_kept() {
+    lease_test(LeaseInput::Returned, LeaseOutput::SameLock).pass();
+}
+
+#[test]
+fn test_shadow_lock_lease_returned_taken_by_renter() {
+    lease_test(LeaseInput::Returned, LeaseOutput::Renter).fail(-127);
+}
+
+/// Delegated to the primary lock 1, the heir lock 4 takes over after 1000 blocks untouched.
+/// A plain cell of the `key` lock is presented, and the shadow cell is spent with the relative `since`.
+fn heir_test(key: u8, since: u64) -> ShadowLockTest {
+    let mut test = ShadowLockTest::new();
+    let primary = test.script(1);
+    let heir = test.script(4);
+    let key = test.script(key);
+
+    let mut heir_value = (RELATIVE_BLOCK_SINCE | 1000).to_le_bytes().to_vec();
+    heir_value.extend(hash_entry(0, hash(&heir)));
+    let shadow = test.shadow_lock(
+        LockArgs::new(0, hash(&primary))
+            .extension(0x13, &heir_value)
+            .build(),
+    );
+
+    test.key(&key);
+    test.input_with_since(cell(500, &shadow), &[], since);
+    test.output(cell(500, &key), &[]);
+    test
+}
+
+#[test]
+fn test_shadow_lock_heir_primary() {
+    heir_test(1, 0).pass();
+}
+
+#[test]
+fn test_shadow_lock_heir_after_period() {
+    heir_test(4, RELATIVE_BLOCK_SINCE | 1000).pass();
+}
+
+#[test]
+fn test_shadow_lock_heir_before_period() {
+    heir_test(4, RELATIVE_BLOCK_SINCE | 999).fail(-128);
+}
+
+#[test]
+fn test_shadow_lock_heir_nobody() {
+    heir_test(3, RELATIVE_BLOCK_SINCE | 1000).fail(-110);
+}